@@ -17,6 +17,7 @@
 */
 use crate::unix_passwd::parse_etc_passwd;
 use configparser::ini::Ini;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::Error;
@@ -25,16 +26,18 @@ use std::path::PathBuf;
 use std::process::Command;
 use tracing::{debug, error};
 
-use crate::constants::MAPPED_NAME_CACHE;
 use crate::constants::{
     CN_NAME_MAPPING, DEFAULT_AUTHORITY_HOST, DEFAULT_BROKER_SOCK_PATH, DEFAULT_CACHE_TIMEOUT,
     DEFAULT_CONFIG_PATH, DEFAULT_CONN_TIMEOUT, DEFAULT_DB_PATH, DEFAULT_HELLO_ENABLED,
     DEFAULT_HELLO_PIN_MIN_LEN, DEFAULT_HELLO_PIN_RETRY_COUNT, DEFAULT_HOME_ALIAS,
     DEFAULT_HOME_ATTR, DEFAULT_HOME_PREFIX, DEFAULT_HSM_PIN_PATH, DEFAULT_ID_ATTR_MAP,
-    DEFAULT_ODC_PROVIDER, DEFAULT_SELINUX, DEFAULT_SFA_FALLBACK_ENABLED, DEFAULT_SHELL,
-    DEFAULT_SOCK_PATH, DEFAULT_TASK_SOCK_PATH, DEFAULT_TPM_TCTI_NAME, DEFAULT_USE_ETC_SKEL,
-    SERVER_CONFIG_PATH,
+    DEFAULT_MAX_POLICIES, DEFAULT_MAX_SETTINGS_PER_POLICY, DEFAULT_ODC_PROVIDER,
+    DEFAULT_POLICY_HTTP_BACKOFF, DEFAULT_POLICY_HTTP_RETRIES, DEFAULT_POLICY_HTTP_TIMEOUT,
+    DEFAULT_POLICY_REFRESH_INTERVAL, DEFAULT_SELINUX, DEFAULT_SFA_FALLBACK_ENABLED, DEFAULT_SHELL,
+    DEFAULT_SOCK_PATH, DEFAULT_SSHD_ALLOWED_DIRECTIVES, DEFAULT_SSHD_RELOAD,
+    DEFAULT_TASK_SOCK_PATH, DEFAULT_TPM_TCTI_NAME, DEFAULT_USE_ETC_SKEL, SERVER_CONFIG_PATH,
 };
+use crate::constants::{MAPPED_NAME_CACHE, MAX_POLICY_HTTP_TIMEOUT};
 use crate::mapping::{MappedNameCache, Mode};
 use crate::unix_config::{HomeAttr, HsmType};
 use himmelblau::error::MsalError;
@@ -106,6 +109,47 @@ async fn request_federation_provider(
     }
 }
 
+/// Network settings for the requests made while refreshing policy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyHttpConfig {
+    /// Seconds to wait for each attempt of a request, at most
+    /// MAX_POLICY_HTTP_TIMEOUT.
+    pub timeout: u64,
+    /// How often a request which failed to reach the service, or was
+    /// throttled, is retried.
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubled for each retry.
+    pub backoff: u64,
+}
+
+impl Default for PolicyHttpConfig {
+    fn default() -> Self {
+        PolicyHttpConfig {
+            timeout: DEFAULT_POLICY_HTTP_TIMEOUT,
+            retries: DEFAULT_POLICY_HTTP_RETRIES,
+            backoff: DEFAULT_POLICY_HTTP_BACKOFF,
+        }
+    }
+}
+
+/// Upper bounds on the policies accepted from Intune.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyLimits {
+    /// The maximum number of policies assigned to the device.
+    pub max_policies: usize,
+    /// The maximum number of settings in a single policy.
+    pub max_settings_per_policy: usize,
+}
+
+impl Default for PolicyLimits {
+    fn default() -> Self {
+        PolicyLimits {
+            max_policies: DEFAULT_MAX_POLICIES,
+            max_settings_per_policy: DEFAULT_MAX_SETTINGS_PER_POLICY,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct HimmelblauConfig {
     config: Ini,
@@ -397,6 +441,208 @@ impl HimmelblauConfig {
         match_bool(self.config.get("global", "apply_policy"), false)
     }
 
+    pub fn get_policy_json_sink(&self) -> Option<String> {
+        self.config.get("global", "policy_json_sink")
+    }
+
+    pub fn get_policy_json_sink_filter(&self) -> Option<String> {
+        self.config.get("global", "policy_json_sink_filter")
+    }
+
+    pub fn get_policy_status_textfile(&self) -> Option<String> {
+        self.config.get("global", "policy_status_textfile")
+    }
+
+    pub fn get_policy_audit_dir(&self) -> Option<String> {
+        self.config.get("global", "policy_audit_dir")
+    }
+
+    pub fn get_policy_debug(&self) -> bool {
+        match_bool(self.config.get("global", "policy_debug"), false)
+    }
+
+    pub fn get_policy_strict_settings(&self) -> bool {
+        match_bool(self.config.get("global", "policy_strict_settings"), false)
+    }
+
+    pub fn get_compliance_report_only(&self) -> bool {
+        match_bool(self.config.get("global", "compliance_report_only"), false)
+    }
+
+    pub fn get_local_policy_file(&self) -> Option<String> {
+        self.config.get("global", "local_policy_file")
+    }
+
+    pub fn get_policy_refresh_interval(&self) -> u64 {
+        match self.config.get("global", "policy_refresh_interval") {
+            Some(val) => match val.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => {
+                    error!(
+                        "Failed parsing policy_refresh_interval from config: {}",
+                        val
+                    );
+                    DEFAULT_POLICY_REFRESH_INTERVAL
+                }
+            },
+            None => DEFAULT_POLICY_REFRESH_INTERVAL,
+        }
+    }
+
+    pub fn get_policy_refresh_deadline(&self) -> Option<u64> {
+        match self.config.get("global", "policy_refresh_deadline") {
+            Some(val) => match val.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) => Some(n),
+                Err(_) => {
+                    error!(
+                        "Failed parsing policy_refresh_deadline from config: {}",
+                        val
+                    );
+                    None
+                }
+            },
+            None => None,
+        }
+    }
+
+    pub fn get_http_proxy(&self) -> Option<String> {
+        self.config.get("global", "http_proxy")
+    }
+
+    pub fn get_no_proxy(&self) -> Option<String> {
+        self.config.get("global", "no_proxy")
+    }
+
+    pub fn get_policy_cache_max_age(&self) -> Option<u64> {
+        match self.config.get("global", "policy_cache_max_age") {
+            Some(val) => match val.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) => Some(n),
+                Err(_) => {
+                    error!("Failed parsing policy_cache_max_age from config: {}", val);
+                    None
+                }
+            },
+            None => None,
+        }
+    }
+
+    pub fn get_policy_limits(&self) -> PolicyLimits {
+        let defaults = PolicyLimits::default();
+        let limit = |key: &str, default: usize| match self.config.get("global", key) {
+            Some(val) => match val.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    error!("Failed parsing {} from config: {}", key, val);
+                    default
+                }
+            },
+            None => default,
+        };
+        PolicyLimits {
+            max_policies: limit("max_policies", defaults.max_policies),
+            max_settings_per_policy: limit(
+                "max_settings_per_policy",
+                defaults.max_settings_per_policy,
+            ),
+        }
+    }
+
+    pub fn get_policy_http_config(&self) -> PolicyHttpConfig {
+        let defaults = PolicyHttpConfig::default();
+        PolicyHttpConfig {
+            timeout: match self.config.get("global", "policy_http_timeout") {
+                Some(val) => match val.parse::<u64>() {
+                    Ok(n) if n > MAX_POLICY_HTTP_TIMEOUT => {
+                        error!(
+                            "policy_http_timeout {} exceeds the request timeout of {} seconds, using {}",
+                            n, MAX_POLICY_HTTP_TIMEOUT, MAX_POLICY_HTTP_TIMEOUT
+                        );
+                        MAX_POLICY_HTTP_TIMEOUT
+                    }
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        error!("Failed parsing policy_http_timeout from config: {}", val);
+                        defaults.timeout
+                    }
+                },
+                None => defaults.timeout,
+            },
+            retries: match self.config.get("global", "policy_http_retries") {
+                Some(val) => match val.parse::<u32>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        error!("Failed parsing policy_http_retries from config: {}", val);
+                        defaults.retries
+                    }
+                },
+                None => defaults.retries,
+            },
+            backoff: match self.config.get("global", "policy_http_backoff") {
+                Some(val) => match val.parse::<u64>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        error!("Failed parsing policy_http_backoff from config: {}", val);
+                        defaults.backoff
+                    }
+                },
+                None => defaults.backoff,
+            },
+        }
+    }
+
+    /// Map of policy id to forced script execution context (root or user).
+    pub fn get_script_execution_context_overrides(&self) -> HashMap<String, String> {
+        match self
+            .config
+            .get("global", "script_execution_context_overrides")
+        {
+            Some(val) => val
+                .split(',')
+                .filter_map(|entry| {
+                    let (policy_id, context) = entry.split_once(':')?;
+                    Some((policy_id.trim().to_string(), context.trim().to_lowercase()))
+                })
+                .collect(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// The interpreters script policies may use, or None if unrestricted.
+    pub fn get_allowed_script_interpreters(&self) -> Option<Vec<String>> {
+        self.config
+            .get("global", "allowed_script_interpreters")
+            .map(|val| {
+                val.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+    }
+
+    /// The sshd_config directives sshd policies may set.
+    pub fn get_sshd_allowed_directives(&self) -> Vec<String> {
+        match self.config.get("global", "sshd_allowed_directives") {
+            Some(val) => val
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            None => DEFAULT_SSHD_ALLOWED_DIRECTIVES
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn get_sshd_reload(&self) -> bool {
+        match_bool(
+            self.config.get("global", "sshd_reload"),
+            DEFAULT_SSHD_RELOAD,
+        )
+    }
+
     pub fn get_pam_allow_groups(&self) -> Vec<String> {
         let mut pam_allow_groups = vec![];
         for section in self.config.sections() {
@@ -575,6 +821,41 @@ impl HimmelblauConfig {
         self.config.get(domain, "graph_url")
     }
 
+    /// Find the domain holding the enrollment data (the discovered tenant,
+    /// authority and Graph url) for a domain. This is the domain itself if it
+    /// is configured, otherwise the domain which lists it as an alias. If
+    /// neither is found and exactly one domain was enrolled, that domain is
+    /// used.
+    pub fn get_enrolled_domain(&self, domain: &str) -> Option<String> {
+        let domains = self.get_configured_domains();
+        if let Some(found) = domains.iter().find(|d| d.eq_ignore_ascii_case(domain)) {
+            if self.config.get(found, "tenant_id").is_some()
+                || self.config.get(found, "graph_url").is_some()
+            {
+                return Some(found.to_string());
+            }
+        }
+        if let Some(found) = domains.iter().find(|d| {
+            self.config
+                .get(d, "domain_aliases")
+                .map(|aliases| {
+                    aliases
+                        .split(',')
+                        .any(|alias| alias.trim().eq_ignore_ascii_case(domain))
+                })
+                .unwrap_or(false)
+        }) {
+            return Some(found.to_string());
+        }
+        let mut enrolled = domains
+            .iter()
+            .filter(|d| self.config.get(d, "intune_device_id").is_some());
+        match (enrolled.next(), enrolled.next()) {
+            (Some(found), None) => Some(found.to_string()),
+            _ => None,
+        }
+    }
+
     pub fn get_local_groups(&self) -> Vec<String> {
         match self.config.get("global", "local_groups") {
             Some(val) => val.split(',').map(|s| s.to_string()).collect(),
@@ -965,6 +1246,211 @@ mod tests {
         assert_eq!(config_empty.get_apply_policy(), false);
     }
 
+    #[test]
+    fn test_get_policy_json_sink() {
+        let config_data = r#"
+        [global]
+        policy_json_sink = /var/lib/himmelblau/policy.json
+        policy_json_sink_filter = ^linux_mount_
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_policy_json_sink(),
+            Some("/var/lib/himmelblau/policy.json".to_string())
+        );
+        assert_eq!(
+            config.get_policy_json_sink_filter(),
+            Some("^linux_mount_".to_string())
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_policy_json_sink(), None);
+        assert_eq!(config_empty.get_policy_json_sink_filter(), None);
+    }
+
+    #[test]
+    fn test_get_policy_http_config() {
+        let config_data = r#"
+        [global]
+        policy_http_timeout = 2
+        policy_http_retries = 5
+        policy_http_backoff = 100
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_policy_http_config(),
+            PolicyHttpConfig {
+                timeout: 2,
+                retries: 5,
+                backoff: 100,
+            }
+        );
+
+        // The timeout can not exceed the request timeout of libhimmelblau
+        let config_data = r#"
+        [global]
+        policy_http_timeout = 30
+        "#;
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+        assert_eq!(
+            config.get_policy_http_config().timeout,
+            MAX_POLICY_HTTP_TIMEOUT
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(
+            config_empty.get_policy_http_config(),
+            PolicyHttpConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_get_policy_refresh_deadline() {
+        let config_data = r#"
+        [global]
+        policy_refresh_deadline = 120
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(config.get_policy_refresh_deadline(), Some(120));
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_policy_refresh_deadline(), None);
+    }
+
+    #[test]
+    fn test_get_http_proxy() {
+        let config_data = r#"
+        [global]
+        http_proxy = http://proxy.example.com:3128
+        no_proxy = localhost,.example.com
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_http_proxy(),
+            Some("http://proxy.example.com:3128".to_string())
+        );
+        assert_eq!(
+            config.get_no_proxy(),
+            Some("localhost,.example.com".to_string())
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_http_proxy(), None);
+        assert_eq!(config_empty.get_no_proxy(), None);
+    }
+
+    #[test]
+    fn test_get_policy_cache_max_age() {
+        let config_data = r#"
+        [global]
+        policy_cache_max_age = 604800
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(config.get_policy_cache_max_age(), Some(604800));
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_policy_cache_max_age(), None);
+    }
+
+    #[test]
+    fn test_get_policy_limits() {
+        let config_data = r#"
+        [global]
+        max_policies = 50
+        max_settings_per_policy = 0
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_policy_limits(),
+            PolicyLimits {
+                max_policies: 50,
+                max_settings_per_policy: DEFAULT_MAX_SETTINGS_PER_POLICY,
+            }
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_policy_limits(), PolicyLimits::default());
+    }
+
+    #[test]
+    fn test_get_policy_status_textfile() {
+        let config_data = r#"
+        [global]
+        policy_status_textfile = /var/lib/node_exporter/textfile_collector/himmelblau_policy.prom
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_policy_status_textfile(),
+            Some("/var/lib/node_exporter/textfile_collector/himmelblau_policy.prom".to_string())
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_policy_status_textfile(), None);
+    }
+
+    #[test]
+    fn test_get_policy_audit_dir() {
+        let config_data = r#"
+        [global]
+        policy_audit_dir = /var/log/himmelblau/policy
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_policy_audit_dir(),
+            Some("/var/log/himmelblau/policy".to_string())
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_policy_audit_dir(), None);
+    }
+
+    #[test]
+    fn test_get_policy_strict_settings() {
+        let config_data = r#"
+        [global]
+        policy_strict_settings = true
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert!(config.get_policy_strict_settings());
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert!(!config_empty.get_policy_strict_settings());
+    }
+
+    #[test]
+    fn test_get_compliance_report_only() {
+        let config_data = r#"
+        [global]
+        compliance_report_only = true
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(config.get_compliance_report_only(), true);
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_compliance_report_only(), false);
+    }
+
     #[test]
     fn test_get_home_attr() {
         let config_data = r#"
@@ -1329,6 +1815,39 @@ mod tests {
         assert_eq!(config_empty.get_graph_url("example.com"), None);
     }
 
+    #[test]
+    fn test_get_enrolled_domain() {
+        let config_data = r#"
+        [global]
+        domains = example.com
+
+        [example.com]
+        tenant_id = 00000000-0000-0000-0000-000000000001
+        graph_url = https://graph.microsoft.com
+        domain_aliases = example.org
+        intune_device_id = 00000000-0000-0000-0000-000000000002
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_enrolled_domain("EXAMPLE.com"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            config.get_enrolled_domain("example.org"),
+            Some("example.com".to_string())
+        );
+        // The only enrolled domain is used for unknown domains
+        assert_eq!(
+            config.get_enrolled_domain("example.net"),
+            Some("example.com".to_string())
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_enrolled_domain("example.com"), None);
+    }
+
     #[test]
     fn test_get_selinux() {
         let config_data = r#"
@@ -1436,6 +1955,68 @@ mod tests {
         assert_eq!(config_empty.get_local_groups(), Vec::<String>::new());
     }
 
+    #[test]
+    fn test_get_sshd_allowed_directives() {
+        let config_data = r#"
+        [global]
+        sshd_allowed_directives = PermitRootLogin, Banner
+        sshd_reload = false
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_sshd_allowed_directives(),
+            vec!["PermitRootLogin".to_string(), "Banner".to_string()]
+        );
+        assert!(!config.get_sshd_reload());
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(
+            config_empty.get_sshd_allowed_directives().len(),
+            DEFAULT_SSHD_ALLOWED_DIRECTIVES.len()
+        );
+        assert!(config_empty.get_sshd_reload());
+    }
+
+    #[test]
+    fn test_get_allowed_script_interpreters() {
+        let config_data = r#"
+        [global]
+        allowed_script_interpreters = /bin/sh, /usr/bin/python3
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        assert_eq!(
+            config.get_allowed_script_interpreters(),
+            Some(vec!["/bin/sh".to_string(), "/usr/bin/python3".to_string()])
+        );
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert_eq!(config_empty.get_allowed_script_interpreters(), None);
+    }
+
+    #[test]
+    fn test_get_script_execution_context_overrides() {
+        let config_data = r#"
+        [global]
+        script_execution_context_overrides = policy1:root, policy2:User,invalid
+        "#;
+
+        let temp_file = create_temp_config(config_data);
+        let config = HimmelblauConfig::new(Some(&temp_file)).unwrap();
+
+        let overrides = config.get_script_execution_context_overrides();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides.get("policy1"), Some(&"root".to_string()));
+        assert_eq!(overrides.get("policy2"), Some(&"user".to_string()));
+        let config_empty = HimmelblauConfig::new(None).unwrap();
+        assert!(config_empty
+            .get_script_execution_context_overrides()
+            .is_empty());
+    }
+
     #[test]
     fn test_get_logon_script() {
         let config_data = r#"
@@ -39,6 +39,30 @@ pub const DEFAULT_APP_ID: &str = "b743a22d-6705-4147-8670-d92fa515ee2b";
 pub const DRS_APP_ID: &str = "01cb2876-7ebd-4aa4-9cc9-d28bd4d359a9";
 pub const DEFAULT_CONN_TIMEOUT: u64 = 30;
 pub const DEFAULT_CACHE_TIMEOUT: u64 = 300;
+pub const DEFAULT_POLICY_REFRESH_INTERVAL: u64 = 5400;
+// libhimmelblau gives up on each Graph and Intune request after 3 seconds
+// (1 second to connect), so a longer policy request timeout has no effect
+pub const MAX_POLICY_HTTP_TIMEOUT: u64 = 3;
+pub const DEFAULT_POLICY_HTTP_TIMEOUT: u64 = MAX_POLICY_HTTP_TIMEOUT;
+pub const DEFAULT_POLICY_HTTP_RETRIES: u32 = 3;
+pub const DEFAULT_POLICY_HTTP_BACKOFF: u64 = 500;
+pub const DEFAULT_MAX_POLICIES: usize = 1000;
+pub const DEFAULT_MAX_SETTINGS_PER_POLICY: usize = 1000;
+pub const DEFAULT_SSHD_ALLOWED_DIRECTIVES: &[&str] = &[
+    "PasswordAuthentication",
+    "PermitRootLogin",
+    "PermitEmptyPasswords",
+    "PubkeyAuthentication",
+    "KbdInteractiveAuthentication",
+    "X11Forwarding",
+    "AllowTcpForwarding",
+    "AllowAgentForwarding",
+    "MaxAuthTries",
+    "LoginGraceTime",
+    "ClientAliveInterval",
+    "ClientAliveCountMax",
+];
+pub const DEFAULT_SSHD_RELOAD: bool = true;
 pub const DEFAULT_SELINUX: bool = true;
 pub const DEFAULT_HSM_PIN_PATH: &str = "/var/lib/himmelblaud/hsm-pin";
 pub const DEFAULT_HELLO_ENABLED: bool = true;
@@ -19,6 +19,7 @@
 #![deny(clippy::needless_pass_by_value)]
 #![deny(clippy::trivially_copy_pass_by_ref)]
 
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::fs::metadata;
 use std::io;
@@ -26,6 +27,7 @@ use std::io::{Error as IoError, ErrorKind};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -33,6 +35,7 @@ use bytes::{BufMut, BytesMut};
 use clap::{Arg, ArgAction, Command};
 use futures::{SinkExt, StreamExt};
 use himmelblau::{ClientInfo, IdToken, UserToken as UnixUserToken};
+use himmelblau_policies::policies::next_refresh_delay;
 use himmelblau_unix_common::config::{split_username, HimmelblauConfig};
 use himmelblau_unix_common::constants::{DEFAULT_APP_ID, DEFAULT_CONFIG_PATH};
 use himmelblau_unix_common::db::{Cache, CacheTxn, Db};
@@ -58,6 +61,7 @@ use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::broadcast;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot;
+use tokio::sync::Mutex;
 use tokio::time;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 use tracing::span;
@@ -159,10 +163,15 @@ fn rm_if_exist(p: &str) {
     }
 }
 
+/// Forward tasks to the tasks daemon, one at a time. Background policy
+/// refreshes arrive on `refresh_channel_rx`, and are only sent when no other
+/// task is queued. `refreshing` is set while a refresh is in flight.
 async fn handle_task_client(
     stream: UnixStream,
     task_channel_tx: &Sender<AsyncTaskRequest>,
     task_channel_rx: &mut Receiver<AsyncTaskRequest>,
+    refresh_channel_rx: &mut Receiver<AsyncTaskRequest>,
+    refreshing: &AtomicBool,
 ) -> Result<(), Box<dyn Error>> {
     // setup the codec
     let mut reqs = Framed::new(stream, TaskCodec);
@@ -170,18 +179,28 @@ async fn handle_task_client(
     loop {
         // TODO wait on the channel OR the task handler, so we know
         // when it closes.
-        let v = match task_channel_rx.recv().await {
-            Some(v) => v,
-            None => return Ok(()),
+        let (v, refresh) = tokio::select! {
+            biased;
+            v = task_channel_rx.recv() => match v {
+                Some(v) => (v, false),
+                None => return Ok(()),
+            },
+            v = refresh_channel_rx.recv() => match v {
+                Some(v) => (v, true),
+                None => return Ok(()),
+            },
         };
 
         debug!("Sending Task -> {:?}", v.0.as_safe_string());
 
         // Write the req to the socket.
+        refreshing.store(refresh, Ordering::SeqCst);
         if let Err(_e) = reqs.send(v.0.clone()).await {
+            refreshing.store(false, Ordering::SeqCst);
             // re-queue the event if not timed out.
             // This is indicated by the one shot being dropped.
-            if !v.1.is_closed() {
+            // A refresh is simply retried at the next interval.
+            if !refresh && !v.1.is_closed() {
                 let _ = task_channel_tx
                     .send_timeout(v, Duration::from_millis(100))
                     .await;
@@ -190,7 +209,9 @@ async fn handle_task_client(
             return Err(Box::new(IoError::new(ErrorKind::Other, "oh no!")));
         }
 
-        match reqs.next().await {
+        let resp = reqs.next().await;
+        refreshing.store(false, Ordering::SeqCst);
+        match resp {
             Some(Ok(TaskResponse::Success(status))) => {
                 debug!("Task was acknowledged and completed.");
                 // Send a result back via the one-shot
@@ -205,10 +226,128 @@ async fn handle_task_client(
     }
 }
 
+/// Why the Intune policies of an account could not be applied.
+#[derive(Debug)]
+enum PolicyApplyError {
+    /// The tasks daemon did not report the outcome in time.
+    TimedOut,
+    Failed(String),
+}
+
+impl std::fmt::Display for PolicyApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyApplyError::TimedOut => write!(f, "Timed out waiting for the tasks daemon"),
+            PolicyApplyError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Ask the tasks daemon to apply the Intune policies of `account_id`, and wait
+/// up to `wait` for the outcome. Returns None if no access tokens could be
+/// obtained for the account, otherwise the status of the apply (0 on success)
+/// or why it could not be requested.
+async fn request_policy_apply(
+    cachelayer: &Resolver<HimmelblauMultiProvider>,
+    task_channel_tx: &Sender<AsyncTaskRequest>,
+    account_id: &str,
+    wait: Duration,
+) -> Option<Result<i32, PolicyApplyError>> {
+    let graph_token = cachelayer
+        .get_user_accesstoken(
+            Id::Name(account_id.to_string()),
+            vec!["00000003-0000-0000-c000-000000000000/.default".to_string()],
+            Some(DEFAULT_APP_ID.to_string()),
+        )
+        .await?;
+    let intune_token = cachelayer
+        .get_user_accesstoken(
+            Id::Name(account_id.to_string()),
+            vec!["0000000a-0000-0000-c000-000000000000/.default".to_string()],
+            Some(DEFAULT_APP_ID.to_string()),
+        )
+        .await?;
+
+    let (tx, rx) = oneshot::channel();
+    if let Err(e) = task_channel_tx
+        .send_timeout(
+            (
+                TaskRequest::ApplyPolicy(
+                    account_id.to_string(),
+                    graph_token.access_token.clone().unwrap_or("".to_string()),
+                    intune_token.access_token.clone().unwrap_or("".to_string()),
+                ),
+                tx,
+            ),
+            Duration::from_millis(500),
+        )
+        .await
+    {
+        return Some(Err(PolicyApplyError::Failed(e.to_string())));
+    }
+    // Now wait for the other end OR timeout.
+    Some(
+        match time::timeout_at(time::Instant::now() + wait, rx).await {
+            Ok(Ok(status)) => Ok(status),
+            Ok(Err(e)) => Err(PolicyApplyError::Failed(e.to_string())),
+            Err(_) => Err(PolicyApplyError::TimedOut),
+        },
+    )
+}
+
+/// Periodically refresh the Intune policies of the accounts which logged on,
+/// every policy_refresh_interval plus a random offset. Refreshes are queued
+/// on `refresh_channel_tx`, behind the tasks of logons.
+async fn refresh_policies(
+    cachelayer: Arc<Resolver<HimmelblauMultiProvider>>,
+    refresh_channel_tx: Sender<AsyncTaskRequest>,
+    policy_accounts: Arc<Mutex<BTreeSet<String>>>,
+    cfg: HimmelblauConfig,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+) {
+    let interval = Duration::from_secs(cfg.get_policy_refresh_interval());
+    // Nobody waits on a background refresh, so allow it the full deadline
+    let wait = Duration::from_secs(cfg.get_policy_refresh_deadline().unwrap_or(300));
+    'refresh: loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {
+                break;
+            }
+            _ = time::sleep(next_refresh_delay(interval, None)) => {
+                let accounts = policy_accounts.lock().await.clone();
+                for account_id in accounts {
+                    let res = tokio::select! {
+                        _ = shutdown_rx.recv() => {
+                            break 'refresh;
+                        }
+                        res = request_policy_apply(&cachelayer, &refresh_channel_tx, &account_id, wait) => res,
+                    };
+                    match res {
+                        Some(Ok(0)) => debug!("Refreshed Intune policies for {}", account_id),
+                        Some(Ok(status)) => error!(
+                            "Refreshing Intune policies for {} failed: Status code: {}",
+                            account_id, status
+                        ),
+                        Some(Err(e)) => error!(
+                            "Refreshing Intune policies for {} failed: {}",
+                            account_id, e
+                        ),
+                        // The account's tokens have expired, until it logs on again
+                        None => debug!("No access token to refresh Intune policies for {}", account_id),
+                    }
+                }
+            }
+        }
+    }
+    info!("Stopped policy refresh");
+}
+
 async fn handle_client(
     sock: UnixStream,
     cachelayer: Arc<Resolver<HimmelblauMultiProvider>>,
     task_channel_tx: &Sender<AsyncTaskRequest>,
+    policy_accounts: Arc<Mutex<BTreeSet<String>>>,
+    refreshing: Arc<AtomicBool>,
     cfg: HimmelblauConfig,
 ) -> Result<(), Box<dyn Error>> {
     trace!("Accepted connection");
@@ -506,93 +645,43 @@ async fn handle_client(
                                                     }
 
                                                     // Apply Intune policies
-                                                    if cfg.get_apply_policy()
-                                                    {
-                                                        let graph_token = cachelayer
-                                                            .get_user_accesstoken(
-                                                                Id::Name(account_id.clone()),
-                                                                vec!["00000003-0000-0000-c000-000000000000/.default".to_string()],
-                                                                Some(DEFAULT_APP_ID.to_string())
-                                                            ).await;
-                                                        let intune_token = cachelayer
-                                                            .get_user_accesstoken(
-                                                                Id::Name(account_id.clone()),
-                                                                vec!["0000000a-0000-0000-c000-000000000000/.default".to_string()],
-                                                                Some(DEFAULT_APP_ID.to_string())
-                                                            ).await;
-
-                                                        if let Some(graph_token) = graph_token {
-                                                            if let Some(intune_token) = intune_token {
-                                                                let (tx, rx) = oneshot::channel();
-
-                                                                match task_channel_tx
-                                                                    .send_timeout(
-                                                                        (
-                                                                            TaskRequest::ApplyPolicy(
-                                                                                account_id.clone(),
-                                                                                graph_token
-                                                                                    .access_token
-                                                                                    .clone()
-                                                                                    .unwrap_or("".to_string()),
-                                                                                intune_token
-                                                                                    .access_token
-                                                                                    .clone()
-                                                                                    .unwrap_or("".to_string()),
-                                                                            ),
-                                                                            tx,
-                                                                        ),
-                                                                        Duration::from_millis(500),
-                                                                    )
-                                                                    .await
-                                                                {
-                                                                    Ok(()) => {
-                                                                        // Now wait for the other end OR timeout.
-                                                                        match time::timeout_at(
-                                                                            time::Instant::now()
-                                                                                + Duration::from_secs(
-                                                                                    5,
-                                                                                ),
-                                                                            rx,
-                                                                        )
-                                                                        .await
-                                                                        {
-                                                                            Ok(Ok(status)) => {
-                                                                                if status == 0 {
-                                                                                    debug!("Successfully applied Intune policies");
-                                                                                } else {
-                                                                                    resp = PamAuthResponse::Denied(
-                                                                                        "Authentication was explicitly denied due to Intune failure".to_string()
-                                                                                    );
-                                                                                }
-                                                                            }
-                                                                            Ok(Err(e)) => {
-                                                                                resp = PamAuthResponse::Denied(
-                                                                                    format!(
-                                                                                        "Authentication was explicitly denied due to Intune failure: {}",
-                                                                                        e
-                                                                                    )
-                                                                                );
-                                                                            }
-                                                                            Err(e) => {
-                                                                                resp = PamAuthResponse::Denied(
-                                                                                    format!(
-                                                                                        "Authentication was explicitly denied due to Intune failure: {}",
-                                                                                        e
-                                                                                    )
-                                                                                );
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                    Err(e) => {
-                                                                        resp = PamAuthResponse::Denied(
-                                                                            format!(
-                                                                                "Authentication was explicitly denied due to Intune failure: {}",
-                                                                                e
-                                                                            )
-                                                                        );
-                                                                    }
-                                                                }
+                                                    if cfg.get_apply_policy() {
+                                                        match request_policy_apply(
+                                                            &cachelayer,
+                                                            task_channel_tx,
+                                                            account_id,
+                                                            Duration::from_secs(5),
+                                                        )
+                                                        .await
+                                                        {
+                                                            Some(Ok(0)) => {
+                                                                debug!("Successfully applied Intune policies");
+                                                                policy_accounts.lock().await.insert(account_id.to_string());
+                                                            }
+                                                            Some(Ok(_)) => {
+                                                                resp = PamAuthResponse::Denied(
+                                                                    "Authentication was explicitly denied due to Intune failure".to_string()
+                                                                );
+                                                            }
+                                                            // The tasks daemon is busy with a background
+                                                            // refresh, which must not deny the logon. The
+                                                            // policies are applied once it completes.
+                                                            Some(Err(PolicyApplyError::TimedOut))
+                                                                if refreshing.load(Ordering::SeqCst) =>
+                                                            {
+                                                                warn!(
+                                                                    "A policy refresh is in progress, applying the Intune policies of {} in the background",
+                                                                    account_id
+                                                                );
+                                                                policy_accounts.lock().await.insert(account_id.to_string());
+                                                            }
+                                                            Some(Err(e)) => {
+                                                                resp = PamAuthResponse::Denied(format!(
+                                                                    "Authentication was explicitly denied due to Intune failure: {}",
+                                                                    e
+                                                                ));
                                                             }
+                                                            None => {}
                                                         }
                                                     }
 
@@ -1102,6 +1191,11 @@ async fn main() -> ExitCode {
             // Setup the tasks socket first.
             let (task_channel_tx, mut task_channel_rx) = channel(16);
             let task_channel_tx = Arc::new(task_channel_tx);
+            // Background policy refreshes are queued separately, so that
+            // they never delay the tasks of a logon
+            let (refresh_channel_tx, mut refresh_channel_rx) = channel(1);
+            let refreshing = Arc::new(AtomicBool::new(false));
+            let task_refreshing = refreshing.clone();
 
             let task_channel_tx_cln = task_channel_tx.clone();
 
@@ -1184,7 +1278,7 @@ async fn main() -> ExitCode {
                                             break;
                                         }
                                         // We have to check for signals here else this tasks waits forever.
-                                        Err(e) = handle_task_client(socket, &task_channel_tx, &mut task_channel_rx) => {
+                                        Err(e) = handle_task_client(socket, &task_channel_tx, &mut task_channel_rx, &mut refresh_channel_rx, &task_refreshing) => {
                                             error!("Task client error occurred; error = {:?}", e);
                                         }
                                     }
@@ -1274,10 +1368,26 @@ async fn main() -> ExitCode {
             // Undo umask changes.
             let _ = unsafe { umask(before) };
 
+            // Accounts which logged on, whose policies are refreshed
+            let policy_accounts = Arc::new(Mutex::new(BTreeSet::new()));
+            let task_e = if cfg.get_apply_policy() {
+                Some(tokio::spawn(refresh_policies(
+                    cachelayer.clone(),
+                    refresh_channel_tx,
+                    policy_accounts.clone(),
+                    cfg.clone(),
+                    broadcast_tx.subscribe(),
+                )))
+            } else {
+                None
+            };
+
             let task_a = tokio::spawn(async move {
                 loop {
                     let tc_tx = task_channel_tx_cln.clone();
                     let cfg_h = cfg.clone();
+                    let policy_accounts_h = policy_accounts.clone();
+                    let refreshing_h = refreshing.clone();
 
                     tokio::select! {
                         _ = broadcast_rx.recv() => {
@@ -1288,7 +1398,7 @@ async fn main() -> ExitCode {
                                 Ok((socket, _addr)) => {
                                     let cachelayer_ref = cachelayer.clone();
                                     tokio::spawn(async move {
-                                        if let Err(e) = handle_client(socket, cachelayer_ref.clone(), &tc_tx, cfg_h).await
+                                        if let Err(e) = handle_client(socket, cachelayer_ref.clone(), &tc_tx, policy_accounts_h, refreshing_h, cfg_h).await
                                         {
                                             error!("handle_client error occurred; error = {:?}", e);
                                         }
@@ -1376,6 +1486,9 @@ async fn main() -> ExitCode {
             let _ = task_b.await;
             let _ = task_c.await;
             let _ = task_d.await;
+            if let Some(task_e) = task_e {
+                let _ = task_e.await;
+            }
 
             ExitCode::SUCCESS
     })
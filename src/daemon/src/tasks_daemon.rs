@@ -32,7 +32,7 @@ use std::{fs, io};
 use bytes::{BufMut, BytesMut};
 use futures::{SinkExt, StreamExt};
 use himmelblau::graph::Graph;
-use himmelblau_policies::policies::apply_intune_policy;
+use himmelblau_policies::policies::{apply_intune_policy, DeviceNotJoined};
 use himmelblau_unix_common::config::{split_username, HimmelblauConfig};
 use himmelblau_unix_common::constants::{DEFAULT_CCACHE_DIR, DEFAULT_CONFIG_PATH};
 use himmelblau_unix_common::unix_proto::{HomeDirectoryInfo, TaskRequest, TaskResponse};
@@ -500,6 +500,11 @@ async fn handle_tasks(stream: UnixStream, cfg: &HimmelblauConfig) {
                     .await
                 {
                     Ok(r) => r,
+                    // Policy is applied once the join completes
+                    Err(e) if e.downcast_ref::<DeviceNotJoined>().is_some() => {
+                        info!("Deferring Intune policies: {}", e);
+                        true
+                    }
                     Err(e) => {
                         error!("Failed to apply Intune policies: {:?}", e);
                         if let Err(e) = reqs
@@ -538,8 +543,39 @@ async fn handle_tasks(stream: UnixStream, cfg: &HimmelblauConfig) {
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> ExitCode {
+/// reqwest reads the proxy of every client the task daemon builds (including
+/// the Graph and Intune clients of libhimmelblau, which can not be passed a
+/// proxy) from the environment. The environment may only be modified while
+/// the process is single threaded, so this runs before the runtime starts.
+fn export_proxy() {
+    // A config which fails to parse is reported by async_main()
+    let Ok(cfg) = HimmelblauConfig::new(Some(DEFAULT_CONFIG_PATH)) else {
+        return;
+    };
+    if let Some(proxy) = cfg.get_http_proxy() {
+        std::env::set_var("HTTPS_PROXY", &proxy);
+        std::env::set_var("HTTP_PROXY", &proxy);
+    }
+    if let Some(no_proxy) = cfg.get_no_proxy() {
+        std::env::set_var("NO_PROXY", no_proxy);
+    }
+}
+
+fn main() -> ExitCode {
+    export_proxy();
+    match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime.block_on(async_main()),
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn async_main() -> ExitCode {
     // let cuid = get_current_uid();
     // let cgid = get_current_gid();
     // We only need to check effective id
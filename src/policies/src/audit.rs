@@ -0,0 +1,120 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+/* Writes a machine readable record of the policies each refresh applied, for
+ * auditing. Every refresh writes a new, dated file, which is never modified
+ * afterwards.
+ */
+use crate::files::install_file;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use himmelblau::intune::IntuneStatus;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// The audit record of the policies applied to `account_id`, with the
+/// expected value, actual value and compliance state of each setting.
+pub fn audit_json(account_id: &str, statuses: &IntuneStatus, timestamp: DateTime<Utc>) -> Value {
+    json!({
+        "account_id": account_id,
+        "device_id": statuses.device_id,
+        "timestamp": timestamp.to_rfc3339(),
+        "policies": statuses
+            .policy_statuses
+            .iter()
+            .map(|policy| json!({
+                "policy_id": policy.policy_id,
+                "settings": policy
+                    .details
+                    .iter()
+                    .map(|detail| json!({
+                        "setting": detail.setting_definition_item_id,
+                        "expected_value": detail.expected_value,
+                        "actual_value": detail.actual_value,
+                        "compliance_state": detail.new_compliance_state,
+                    }))
+                    .collect::<Vec<Value>>(),
+            }))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+fn audit_path(dir: &Path, account_id: &str, timestamp: DateTime<Utc>) -> Result<PathBuf> {
+    if account_id.is_empty() || account_id.contains('/') || account_id.starts_with('.') {
+        return Err(anyhow!("Invalid account id '{}'", account_id));
+    }
+    Ok(dir.join(format!(
+        "{}-{}.json",
+        account_id,
+        timestamp.format("%Y%m%dT%H%M%S%.3fZ")
+    )))
+}
+
+/// Write the audit record of a refresh for `account_id` to a new file in
+/// `dir`, readable by root only. Returns the path of the file.
+pub async fn write_audit(dir: &Path, account_id: &str, statuses: &IntuneStatus) -> Result<PathBuf> {
+    let timestamp = Utc::now();
+    let path = audit_path(dir, account_id, timestamp)?;
+    let record = serde_json::to_string_pretty(&audit_json(account_id, statuses, timestamp))?;
+    install_file(&path, record.as_bytes(), 0o600, None).await?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_audit_json() -> Result<()> {
+        let timestamp = Utc
+            .timestamp_opt(1700000000, 0)
+            .single()
+            .ok_or(anyhow!("Invalid timestamp"))?;
+        let statuses = IntuneStatus {
+            device_id: Some("dev".to_string()),
+            policy_statuses: vec![policy("a", &[("linux_sshd_x11forwarding", "no")])],
+        };
+        assert_eq!(
+            audit_json("tux@example.com", &statuses, timestamp),
+            json!({
+                "account_id": "tux@example.com",
+                "device_id": "dev",
+                "timestamp": "2023-11-14T22:13:20+00:00",
+                "policies": [{
+                    "policy_id": "a",
+                    "settings": [{
+                        "setting": "linux_sshd_x11forwarding",
+                        "expected_value": "no",
+                        "actual_value": "",
+                        "compliance_state": "Error",
+                    }],
+                }],
+            })
+        );
+        assert!(audit_path(
+            Path::new("/var/log/himmelblau"),
+            "tux@example.com",
+            timestamp
+        )
+        .is_ok_and(|path| path
+            == Path::new("/var/log/himmelblau/tux@example.com-20231114T221320.000Z.json")));
+        assert!(audit_path(Path::new("/var/log/himmelblau"), "../tux", timestamp).is_err());
+        Ok(())
+    }
+}
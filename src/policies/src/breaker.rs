@@ -0,0 +1,173 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* A circuit breaker for the policy service, keyed by Graph url. Once the
+ * service could not be reached on several consecutive refreshes, refreshes
+ * stop contacting it and enforce the last applied policies instead. After a
+ * cool-down a single refresh probes the service again.
+ */
+use std::collections::HashMap;
+use std::sync::{Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Open after this many consecutive failures
+const FAILURE_THRESHOLD: u32 = 3;
+// Failures further apart than this are not counted together
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+// How long to stop contacting the service once open
+const COOL_DOWN: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests are sent to the service.
+    Closed,
+    /// The service is not contacted until the cool-down has passed.
+    Open { remaining: Duration },
+    /// A single request is probing whether the service has recovered.
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    failures: u32,
+    first_failure: Option<Instant>,
+    opened: Option<Instant>,
+    /// When the outstanding probe was permitted.
+    probe: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Whether the service may be contacted. Once the cool-down has passed,
+    /// this permits exactly one probe until its outcome is recorded. A probe
+    /// whose outcome is never recorded (e.g. the refresh was cancelled) is
+    /// treated as failed once another cool-down has passed.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let opened = match self.opened {
+            None => return true,
+            Some(opened) => opened,
+        };
+        if now.duration_since(self.probe.unwrap_or(opened)) >= COOL_DOWN {
+            self.probe = Some(now);
+            return true;
+        }
+        false
+    }
+
+    /// The service responded, close the breaker.
+    pub fn record_success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    /// The service could not be reached.
+    pub fn record_failure(&mut self, now: Instant) {
+        if self.probe.is_some() {
+            // The probe failed, start another cool-down
+            self.probe = None;
+            self.opened = Some(now);
+            return;
+        }
+        match self.first_failure {
+            Some(first) if now.duration_since(first) <= FAILURE_WINDOW => self.failures += 1,
+            _ => {
+                self.first_failure = Some(now);
+                self.failures = 1;
+            }
+        }
+        if self.failures >= FAILURE_THRESHOLD {
+            self.opened = Some(now);
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BreakerState {
+        match self.opened {
+            None => BreakerState::Closed,
+            Some(_) if self.probe.is_some() => BreakerState::HalfOpen,
+            Some(opened) => match COOL_DOWN.checked_sub(now.duration_since(opened)) {
+                Some(remaining) if !remaining.is_zero() => BreakerState::Open { remaining },
+                _ => BreakerState::HalfOpen,
+            },
+        }
+    }
+}
+
+/// Run `f` with the circuit breaker for a Graph url.
+pub fn with_breaker<T>(graph_url: &str, f: impl FnOnce(&mut CircuitBreaker) -> T) -> T {
+    static BREAKERS: OnceLock<StdMutex<HashMap<String, CircuitBreaker>>> = OnceLock::new();
+    let mut breakers = match BREAKERS.get_or_init(Default::default).lock() {
+        Ok(breakers) => breakers,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    f(breakers.entry(graph_url.to_lowercase()).or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::default();
+
+        // A success resets the count of consecutive failures
+        breaker.record_failure(start);
+        breaker.record_failure(start);
+        breaker.record_success();
+        breaker.record_failure(start);
+        assert_eq!(breaker.state(start), BreakerState::Closed);
+        assert!(breaker.allow(start));
+
+        // Failures outside the window are not counted together
+        let later = start + FAILURE_WINDOW + Duration::from_secs(1);
+        breaker.record_failure(later);
+        breaker.record_failure(later);
+        assert_eq!(breaker.state(later), BreakerState::Closed);
+
+        breaker.record_failure(later);
+        assert_eq!(
+            breaker.state(later),
+            BreakerState::Open {
+                remaining: COOL_DOWN
+            }
+        );
+        assert!(!breaker.allow(later));
+
+        // Only a single probe is permitted after the cool-down
+        let probe = later + COOL_DOWN;
+        assert_eq!(breaker.state(probe), BreakerState::HalfOpen);
+        assert!(breaker.allow(probe));
+        assert!(!breaker.allow(probe));
+
+        // A failed probe starts another cool-down
+        breaker.record_failure(probe);
+        assert!(!breaker.allow(probe));
+        assert!(breaker.allow(probe + COOL_DOWN));
+        breaker.record_success();
+        assert_eq!(breaker.state(probe + COOL_DOWN), BreakerState::Closed);
+
+        // A probe which never reports its outcome is eventually replaced
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(start);
+        }
+        let probe = start + COOL_DOWN;
+        assert!(breaker.allow(probe));
+        assert!(!breaker.allow(probe + COOL_DOWN - Duration::from_secs(1)));
+        assert!(breaker.allow(probe + COOL_DOWN));
+        assert!(!breaker.allow(probe + COOL_DOWN));
+    }
+}
@@ -24,7 +24,7 @@ use os_release::OsRelease;
 use semver::Version;
 use tokio::fs;
 use tokio::process::Command;
-use tracing::debug;
+use tracing::{debug, error, warn};
 
 pub async fn is_disk_encrypted() -> bool {
     // Check for LUKS encryption using `lsblk`
@@ -111,9 +111,23 @@ impl CSE for ComplianceCSE {
         }
     }
 
+    fn name(&self) -> &'static str {
+        "compliance"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[
+            "linux_distribution_",
+            "linux_deviceencryption_",
+            "linux_passwordpolicy_",
+        ]
+    }
+
     /// Process a group of policies. For deleted policies, no action is taken.
     /// For changed policies, run compliance checks and return an error if any check fails.
     async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        // Evaluate every policy, even if an earlier one fails
+        let mut errors = vec![];
         for policy in policies.policy_statuses.iter_mut() {
             // Validate this is a compliance policy
             if policy.details.iter().any(|detail| {
@@ -122,9 +136,15 @@ impl CSE for ComplianceCSE {
                     || id.starts_with("linux_deviceencryption_")
                     || id.starts_with("linux_passwordpolicy_")
             }) {
-                self.apply_compliance(policy).await?;
+                if let Err(e) = self.apply_compliance(policy).await {
+                    error!("Compliance policy {} failed: {:?}", policy.policy_id, e);
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                }
             }
         }
+        if !errors.is_empty() {
+            return Err(anyhow!("{}", errors.join("; ")));
+        }
         Ok(true)
     }
 }
@@ -163,14 +183,18 @@ impl ComplianceCSE {
                     details.actual_value = system_distro.clone();
                 }
                 "linux_distribution_alloweddistros_item_minimumversion" => {
-                    let min_semver = Version::parse(&normalize_version(&details.expected_value))
-                        .map_err(|e| {
-                            anyhow!(
-                                "Failed to parse minimum version '{}' as semver: {}",
-                                details.expected_value,
-                                e
-                            )
-                        })?;
+                    details.actual_value = os_release.version_id.clone();
+                    let min_semver =
+                        match Version::parse(&normalize_version(&details.expected_value)) {
+                            Ok(min_semver) => min_semver,
+                            Err(e) => {
+                                errors.push(format!(
+                                    "Failed to parse minimum version '{}' as semver: {}",
+                                    details.expected_value, e
+                                ));
+                                continue;
+                            }
+                        };
                     if system_version < min_semver {
                         errors.push(format!(
                                 "Version compliance failed: system version '{}' is less than minimum '{}'",
@@ -183,17 +207,20 @@ impl ComplianceCSE {
                             os_release.version_id
                         );
                     }
-                    details.actual_value = os_release.version_id.clone();
                 }
                 "linux_distribution_alloweddistros_item_maximumversion" => {
-                    let max_semver = Version::parse(&normalize_version(&details.expected_value))
-                        .map_err(|e| {
-                            anyhow!(
-                                "Failed to parse maximum version '{}' as semver: {}",
-                                details.expected_value,
-                                e
-                            )
-                        })?;
+                    details.actual_value = os_release.version_id.clone();
+                    let max_semver =
+                        match Version::parse(&normalize_version(&details.expected_value)) {
+                            Ok(max_semver) => max_semver,
+                            Err(e) => {
+                                errors.push(format!(
+                                    "Failed to parse maximum version '{}' as semver: {}",
+                                    details.expected_value, e
+                                ));
+                                continue;
+                            }
+                        };
                     if system_version > max_semver {
                         errors.push(format!(
                                 "Version compliance failed: system version '{}' is greater than maximum '{}'",
@@ -206,7 +233,6 @@ impl ComplianceCSE {
                             os_release.version_id
                         );
                     }
-                    details.actual_value = os_release.version_id.clone();
                 }
                 "linux_deviceencryption_required" => {
                     let is_disk_encrypted = is_disk_encrypted().await;
@@ -252,6 +278,13 @@ impl ComplianceCSE {
 
         if errors.is_empty() {
             Ok(())
+        } else if self.config.get_compliance_report_only() {
+            // The non-compliant state is still reported to Intune
+            warn!(
+                "Compliance check failures (report only): {}",
+                errors.join("; ")
+            );
+            Ok(())
         } else {
             Err(anyhow!("Compliance check failures: {}", errors.join("; ")))
         }
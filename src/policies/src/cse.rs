@@ -19,15 +19,137 @@
 /* Provides a trait which specifies a Client Side Extension for applying
  * Intune policy.
  */
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use himmelblau::intune::IntuneStatus;
 use himmelblau_unix_common::config::HimmelblauConfig;
+use std::sync::Arc;
 
 #[async_trait]
 pub trait CSE: Send + Sync {
     fn new(config: &HimmelblauConfig, username: &str) -> Self
     where
         Self: Sized;
+    /// A unique name for this extension, referenced by run_after().
+    fn name(&self) -> &'static str;
+    /// Names of the extensions which must be processed before this one.
+    /// Extensions which are not loaded are ignored.
+    fn run_after(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Prefixes of the setting ids applied by this extension.
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Apply the assigned policies. Settings which can be applied on their
+    /// own are applied independently: a malformed setting is logged, left
+    /// non-compliant and included in the returned error, while the remaining
+    /// settings are still applied. Settings which only take effect together
+    /// (e.g. a script and its execution context) fail as a whole.
     async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool>;
+    /// Describe what process_group_policy() would apply, without changing the
+    /// system. By default this lists the settings matching setting_prefixes(),
+    /// as "<policy id>: <setting id>".
+    fn preview_group_policy(&self, policies: &IntuneStatus) -> Vec<String> {
+        policies
+            .policy_statuses
+            .iter()
+            .flat_map(|policy| {
+                policy.details.iter().filter_map(move |detail| {
+                    let id = &detail.setting_definition_item_id;
+                    self.setting_prefixes()
+                        .iter()
+                        .any(|prefix| id.starts_with(prefix))
+                        .then(|| format!("{}: {}", policy.policy_id, id))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Sort extensions so that each runs after those named in its run_after().
+/// Otherwise the given order is preserved. Fails if the dependencies form a
+/// cycle.
+pub fn order_extensions(extensions: Vec<Arc<dyn CSE>>) -> Result<Vec<Arc<dyn CSE>>> {
+    let names: Vec<&str> = extensions.iter().map(|ext| ext.name()).collect();
+    let mut pending = extensions;
+    let mut ordered: Vec<Arc<dyn CSE>> = Vec::with_capacity(pending.len());
+
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|ext| {
+            ext.run_after()
+                .iter()
+                .all(|dep| !names.contains(dep) || ordered.iter().any(|done| done.name() == *dep))
+        });
+        match ready {
+            Some(idx) => ordered.push(pending.remove(idx)),
+            None => {
+                return Err(anyhow!(
+                    "Cyclic ordering between extensions: {}",
+                    pending
+                        .iter()
+                        .map(|ext| ext.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestCSE {
+        name: &'static str,
+        run_after: &'static [&'static str],
+    }
+
+    #[async_trait]
+    impl CSE for TestCSE {
+        fn new(_config: &HimmelblauConfig, _username: &str) -> Self {
+            TestCSE {
+                name: "",
+                run_after: &[],
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn run_after(&self) -> &'static [&'static str] {
+            self.run_after
+        }
+
+        async fn process_group_policy(&self, _policies: &mut IntuneStatus) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn ext(name: &'static str, run_after: &'static [&'static str]) -> Arc<dyn CSE> {
+        Arc::new(TestCSE { name, run_after })
+    }
+
+    fn names(extensions: &[Arc<dyn CSE>]) -> Vec<&'static str> {
+        extensions.iter().map(|ext| ext.name()).collect()
+    }
+
+    #[test]
+    fn test_order_extensions() {
+        let ordered = order_extensions(vec![
+            ext("proxy", &["certificates"]),
+            ext("scripts", &[]),
+            ext("certificates", &["missing"]),
+        ]);
+        assert_eq!(
+            ordered.map(|ordered| names(&ordered)).ok(),
+            Some(vec!["scripts", "certificates", "proxy"])
+        );
+
+        let cyclic = order_extensions(vec![ext("a", &["b"]), ext("b", &["a"])]);
+        assert!(cyclic.is_err());
+    }
 }
@@ -0,0 +1,471 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{
+    forget_provenance, install_file, load_provenance, provenance_registry, Provenance,
+};
+use crate::resolve::{mark_overridden, resolve, Resolved};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::IntuneStatus;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+const SETTING_PREFIX: &str = "linux_dconf_";
+const DCONF_PROFILE: &str = "/etc/dconf/profile/user";
+const DCONF_KEYFILE: &str = "/etc/dconf/db/local.d/50-himmelblau";
+const DCONF_LOCKS: &str = "/etc/dconf/db/local.d/locks/50-himmelblau";
+// The deepest nesting of arrays accepted in a dconf value
+const MAX_VALUE_DEPTH: usize = 8;
+
+/// A single dconf setting. Each linux_dconf_* setting carries one of these as
+/// a JSON document, e.g.
+/// {"key": "/org/gnome/desktop/session/idle-delay", "value": 300, "type": "u", "locked": true}
+#[derive(Debug, Deserialize)]
+struct DconfSetting {
+    key: String,
+    value: Value,
+    /// An optional GVariant type string, for values which are not an int32,
+    /// double, boolean, string or array of strings.
+    #[serde(rename = "type")]
+    value_type: Option<String>,
+    /// Locked keys can not be changed by the user, otherwise the value is
+    /// only a default.
+    #[serde(default)]
+    locked: bool,
+}
+
+fn quote(val: &str) -> String {
+    format!("'{}'", val.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Convert a JSON value to the GVariant text format.
+fn to_gvariant(value: &Value) -> Result<String> {
+    to_gvariant_depth(value, 0)
+}
+
+fn to_gvariant_depth(value: &Value, depth: usize) -> Result<String> {
+    if depth > MAX_VALUE_DEPTH {
+        return Err(anyhow!(
+            "dconf value is nested deeper than {} arrays",
+            MAX_VALUE_DEPTH
+        ));
+    }
+    match value {
+        Value::Bool(val) => Ok(val.to_string()),
+        Value::Number(val) => match val.as_i64() {
+            Some(val) => Ok(val.to_string()),
+            None => {
+                let val = val.as_f64().ok_or(anyhow!("Invalid number {}", val))?;
+                let val = val.to_string();
+                // Doubles require a decimal point
+                if val.contains(['.', 'e', 'E']) {
+                    Ok(val)
+                } else {
+                    Ok(format!("{}.0", val))
+                }
+            }
+        },
+        Value::String(val) => Ok(quote(val)),
+        Value::Array(vals) => Ok(format!(
+            "[{}]",
+            vals.iter()
+                .map(|val| to_gvariant_depth(val, depth + 1))
+                .collect::<Result<Vec<_>>>()?
+                .join(", ")
+        )),
+        Value::Null | Value::Object(_) => Err(anyhow!("Unsupported dconf value {}", value)),
+    }
+}
+
+impl DconfSetting {
+    /// Split the key into its dconf directory and key name.
+    fn split_key(&self) -> Result<(&str, &str)> {
+        let valid = self
+            .key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/-_.".contains(c))
+            && !self.key.contains("//");
+        match self
+            .key
+            .strip_prefix('/')
+            .and_then(|key| key.rsplit_once('/'))
+        {
+            Some((dir, name)) if valid && !name.is_empty() => Ok((dir, name)),
+            _ => Err(anyhow!("Invalid dconf key '{}'", self.key)),
+        }
+    }
+
+    fn gvariant(&self) -> Result<String> {
+        let value = to_gvariant(&self.value)?;
+        match &self.value_type {
+            Some(value_type)
+                if value_type
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "(){}".contains(c)) =>
+            {
+                Ok(format!("@{} {}", value_type, value))
+            }
+            Some(value_type) => Err(anyhow!("Invalid GVariant type '{}'", value_type)),
+            None => Ok(value),
+        }
+    }
+}
+
+/// Render the dconf keyfile and the list of locked keys.
+fn render(settings: &BTreeMap<String, (String, bool)>) -> Result<(String, String)> {
+    let mut dirs: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+    let mut locks = String::new();
+    for (key, (value, locked)) in settings {
+        let (dir, name) = key
+            .strip_prefix('/')
+            .and_then(|key| key.rsplit_once('/'))
+            .ok_or(anyhow!("Invalid dconf key '{}'", key))?;
+        dirs.entry(dir).or_default().push((name, value));
+        if *locked {
+            locks.push_str(&format!("{}\n", key));
+        }
+    }
+    let mut keyfile = String::from("# Managed by himmelblau\n");
+    for (dir, keys) in dirs {
+        keyfile.push_str(&format!("\n[{}]\n", dir));
+        for (name, value) in keys {
+            keyfile.push_str(&format!("{}={}\n", name, value));
+        }
+    }
+    Ok((keyfile, locks))
+}
+
+/// A dconf value, and whether the key is locked.
+struct DconfValue {
+    value: String,
+    locked: bool,
+}
+
+/// Collect the dconf settings of all policies by key. Settings which can not
+/// be parsed, or whose values contain control characters (which would break
+/// the keyfile), are added to `errors`.
+fn resolve_settings(
+    policies: &IntuneStatus,
+    errors: &mut Vec<String>,
+) -> BTreeMap<String, Resolved<DconfValue>> {
+    resolve(policies, SETTING_PREFIX, "dconf key", errors, |_, value| {
+        let setting = serde_json::from_str::<DconfSetting>(value)?;
+        setting.split_key()?;
+        let gvariant = setting.gvariant()?;
+        if gvariant.chars().any(|c| c.is_control()) {
+            return Err(anyhow!("Invalid value for dconf key '{}'", setting.key));
+        }
+        let value = DconfValue {
+            value: gvariant,
+            locked: setting.locked,
+        };
+        Ok(Some((setting.key, value)))
+    })
+}
+
+pub struct DconfCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for DconfCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        DconfCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "dconf"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// The dconf settings of all assigned policies are written to a single
+    /// keyfile in the local system database, and dconf update is run on every
+    /// refresh, so that a failed update is retried. If no dconf settings are
+    /// assigned, the keyfile is removed, unless every assigned setting was
+    /// rejected, in which case the previous keyfile is kept. When several
+    /// policies set the same key, only the setting of the policy which takes
+    /// precedence is reported compliant, and the others non-compliant.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let mut errors = vec![];
+        let resolved = resolve_settings(policies, &mut errors);
+
+        let mut settings: BTreeMap<String, (String, bool)> = BTreeMap::new();
+        let mut sources = BTreeSet::new();
+        let mut setting_ids = vec![];
+        let mut applied = vec![];
+        for (key, setting) in resolved {
+            if let Some(policy) = policies.policy_statuses.get(setting.policy) {
+                if let Some(detail) = policy.details.get(setting.detail) {
+                    sources.insert(policy.policy_id.clone());
+                    setting_ids.push(detail.setting_definition_item_id.clone());
+                }
+            }
+            mark_overridden(
+                policies,
+                (setting.policy, setting.detail),
+                &setting.overridden,
+            );
+            applied.push((setting.policy, setting.detail));
+            settings.insert(key, (setting.value.value, setting.value.locked));
+        }
+
+        let registry = provenance_registry(&self.config)?;
+        if settings.is_empty() {
+            // The provenance of the keyfile is kept until dconf update
+            // succeeds after its removal
+            let managed = Path::new(DCONF_KEYFILE).exists()
+                || load_provenance(&registry)
+                    .await?
+                    .contains_key(DCONF_KEYFILE);
+            if !managed || !errors.is_empty() {
+                return self.result(&errors);
+            }
+            for path in [DCONF_KEYFILE, DCONF_LOCKS] {
+                if Path::new(path).exists() {
+                    fs::remove_file(path)
+                        .await
+                        .map_err(|e| anyhow!("Failed to remove {}: {}", path, e))?;
+                }
+            }
+            debug!("Removed dconf policy {}", DCONF_KEYFILE);
+        } else {
+            self.ensure_profile().await?;
+            let (keyfile, locks) = render(&settings)?;
+            let provenance =
+                Provenance::new(&self.config, sources.into_iter().collect(), setting_ids)?;
+            let keyfile_changed = install_file(
+                Path::new(DCONF_KEYFILE),
+                keyfile.as_bytes(),
+                0o644,
+                Some(&provenance),
+            )
+            .await?;
+            let locks_changed = install_file(
+                Path::new(DCONF_LOCKS),
+                locks.as_bytes(),
+                0o644,
+                Some(&provenance),
+            )
+            .await?;
+            if keyfile_changed || locks_changed {
+                debug!("Updated dconf policy {}", DCONF_KEYFILE);
+            }
+        }
+
+        // Always update, an earlier update may have failed
+        let output = Command::new("dconf")
+            .arg("update")
+            .output()
+            .await
+            .map_err(|e| anyhow!("Failed to execute dconf: {}", e))?;
+        if !output.status.success() {
+            errors.push(format!(
+                "dconf update failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+            return self.result(&errors);
+        }
+        if settings.is_empty() {
+            forget_provenance(
+                &registry,
+                &[PathBuf::from(DCONF_KEYFILE), PathBuf::from(DCONF_LOCKS)],
+            )
+            .await?;
+        }
+        for (p, d) in applied {
+            if let Some(detail) = policies
+                .policy_statuses
+                .get_mut(p)
+                .and_then(|policy| policy.details.get_mut(d))
+            {
+                detail.actual_value = detail.expected_value.clone();
+                detail.new_compliance_state = "Compliant".to_string();
+            }
+        }
+        self.result(&errors)
+    }
+}
+
+impl DconfCSE {
+    /// The local system database is only read if it is listed in the user
+    /// profile. Create the profile if there is none, but never modify an
+    /// existing profile.
+    async fn ensure_profile(&self) -> Result<()> {
+        match fs::read_to_string(DCONF_PROFILE).await {
+            Ok(profile) => {
+                if !profile.lines().any(|line| line.trim() == "system-db:local") {
+                    warn!(
+                        "{} does not include system-db:local, dconf policy will not apply",
+                        DCONF_PROFILE
+                    );
+                }
+                Ok(())
+            }
+            Err(_) => {
+                install_file(
+                    Path::new(DCONF_PROFILE),
+                    b"user-db:user\nsystem-db:local\n",
+                    0o644,
+                    None,
+                )
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    fn result(&self, errors: &[String]) -> Result<bool> {
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply dconf policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(json: &str) -> Result<DconfSetting> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    #[test]
+    fn test_gvariant() {
+        let gvariant = |json: &str| setting(json).and_then(|s| s.gvariant()).ok();
+        assert_eq!(
+            gvariant(r#"{"key": "/a/b", "value": 300, "type": "u"}"#).as_deref(),
+            Some("@u 300")
+        );
+        assert_eq!(
+            gvariant(r#"{"key": "/a/b", "value": false}"#).as_deref(),
+            Some("false")
+        );
+        assert_eq!(
+            gvariant(r#"{"key": "/a/b", "value": 1.5}"#).as_deref(),
+            Some("1.5")
+        );
+        assert_eq!(
+            gvariant(r#"{"key": "/a/b", "value": ["firefox.desktop", "it's.desktop"]}"#).as_deref(),
+            Some(r"['firefox.desktop', 'it\'s.desktop']")
+        );
+        assert_eq!(gvariant(r#"{"key": "/a/b", "value": null}"#), None);
+        let nested = |depth: usize| {
+            format!(
+                r#"{{"key": "/a/b", "value": {}1{}}}"#,
+                "[".repeat(depth),
+                "]".repeat(depth)
+            )
+        };
+        assert!(gvariant(&nested(MAX_VALUE_DEPTH)).is_some());
+        assert_eq!(gvariant(&nested(MAX_VALUE_DEPTH + 1)), None);
+        assert_eq!(
+            gvariant(r#"{"key": "/a/b", "value": 1, "type": "u\n[x]"}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        assert!(setting(r#"{"key": "org/gnome/x", "value": 1}"#)
+            .and_then(|s| s.split_key().map(|_| ()))
+            .is_err());
+        assert!(setting(r#"{"key": "/org/gnome/", "value": 1}"#)
+            .and_then(|s| s.split_key().map(|_| ()))
+            .is_err());
+
+        let settings = BTreeMap::from([
+            (
+                "/org/gnome/desktop/session/idle-delay".to_string(),
+                ("@u 300".to_string(), true),
+            ),
+            (
+                "/org/gnome/desktop/screensaver/lock-enabled".to_string(),
+                ("true".to_string(), false),
+            ),
+        ]);
+        let (keyfile, locks) = render(&settings).unwrap_or_default();
+        assert_eq!(
+            keyfile,
+            "# Managed by himmelblau\n\
+            \n[org/gnome/desktop/screensaver]\nlock-enabled=true\n\
+            \n[org/gnome/desktop/session]\nidle-delay=@u 300\n"
+        );
+        assert_eq!(locks, "/org/gnome/desktop/session/idle-delay\n");
+    }
+
+    #[test]
+    fn test_resolve_settings() {
+        use crate::snapshot::tests::policy as dconf;
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                dconf(
+                    "a",
+                    &[
+                        (
+                            "linux_dconf_idle",
+                            r#"{"key": "/org/gnome/idle-delay", "value": 300}"#,
+                        ),
+                        (
+                            "linux_dconf_lock",
+                            r#"{"key": "/org/gnome/lock-enabled", "value": true}"#,
+                        ),
+                    ],
+                ),
+                dconf(
+                    "b",
+                    &[
+                        (
+                            "linux_dconf_idle",
+                            r#"{"key": "/org/gnome/idle-delay", "value": 600}"#,
+                        ),
+                        ("linux_dconf_bad", r#"{"key": "org/gnome/x", "value": 1}"#),
+                        (
+                            "linux_dconf_newline",
+                            r#"{"key": "/org/gnome/x", "value": "a\n[org/gnome]\nx=1"}"#,
+                        ),
+                    ],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let resolved = resolve_settings(&policies, &mut errors);
+        assert_eq!(errors.len(), 2);
+        assert!(!resolved.contains_key("/org/gnome/x"));
+        let idle = resolved.get("/org/gnome/idle-delay");
+        assert!(idle.is_some_and(|s| s.value.value == "600" && s.policy == 1));
+        let lock = resolved.get("/org/gnome/lock-enabled");
+        assert!(lock.is_some_and(|s| s.value.value == "true" && s.policy == 0));
+    }
+}
@@ -0,0 +1,484 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Helpers shared by the Client Side Extensions for writing and removing the
+ * files they manage.
+ */
+use anyhow::{anyhow, Result};
+use himmelblau::intune::PolicyStatus;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::CString;
+use std::fs::Permissions;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// The policies which produced an installed file, as recorded in the
+/// provenance registry.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProvenanceRecord {
+    pub policy_ids: Vec<String>,
+    pub settings: Vec<String>,
+    /// RFC 3339 time the file was last installed.
+    pub applied: String,
+}
+
+/// The provenance to record for a file when installing it. Records are kept
+/// in a registry beside the cache database, rather than in the managed
+/// files, since not every file format permits comments and some managed
+/// directories (such as dconf databases) would pick up a sidecar file.
+pub struct Provenance {
+    registry: PathBuf,
+    policy_ids: Vec<String>,
+    settings: Vec<String>,
+}
+
+impl Provenance {
+    pub fn new(
+        config: &HimmelblauConfig,
+        policy_ids: Vec<String>,
+        settings: Vec<String>,
+    ) -> Result<Self> {
+        Ok(Provenance {
+            registry: provenance_registry(config)?,
+            policy_ids,
+            settings,
+        })
+    }
+
+    /// The provenance of a file produced from the settings of `policy`
+    /// matching `prefix`.
+    pub fn for_policy(
+        config: &HimmelblauConfig,
+        policy: &PolicyStatus,
+        prefix: &str,
+    ) -> Result<Self> {
+        Provenance::new(
+            config,
+            vec![policy.policy_id.clone()],
+            policy
+                .details
+                .iter()
+                .filter(|detail| detail.setting_definition_item_id.starts_with(prefix))
+                .map(|detail| detail.setting_definition_item_id.clone())
+                .collect(),
+        )
+    }
+}
+
+pub fn provenance_registry(config: &HimmelblauConfig) -> Result<PathBuf> {
+    let mut path = PathBuf::from(config.get_db_path());
+    if !path.pop() {
+        return Err(anyhow!("Failed to determine provenance registry path"));
+    }
+    path.push("policy_provenance.json");
+    Ok(path)
+}
+
+/// Load the provenance of every installed file, keyed by path.
+pub async fn load_provenance(registry: &Path) -> Result<BTreeMap<String, ProvenanceRecord>> {
+    match fs::read_to_string(registry).await {
+        Ok(data) => Ok(serde_json::from_str(&data)?),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+async fn update_provenance<F>(registry: &Path, update: F) -> Result<()>
+where
+    F: FnOnce(&mut BTreeMap<String, ProvenanceRecord>),
+{
+    // Policies for several users may be applied concurrently
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+    let _guard = LOCK.get_or_init(Default::default).lock().await;
+    let mut records = load_provenance(registry).await?;
+    update(&mut records);
+    let data = serde_json::to_string_pretty(&records)?;
+    replace_file(registry, data.as_bytes(), 0o600, None).await?;
+    Ok(())
+}
+
+/// Remove the provenance of files which have been removed.
+pub async fn forget_provenance(registry: &Path, paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    update_provenance(registry, |records| {
+        for path in paths {
+            records.remove(&path.display().to_string());
+        }
+    })
+    .await
+}
+
+/// The owner of a user-scoped file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileOwner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FileOwner {
+    /// Resolve the POSIX user an account is mapped to by the name mapping of
+    /// the daemon. Returns None if the user is not (yet) known to the system.
+    pub fn for_account(config: &HimmelblauConfig, account_id: &str) -> Option<Self> {
+        let name = CString::new(config.map_upn_to_name(account_id)).ok()?;
+        let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+        let mut buf = vec![0; 2048];
+        let mut result = std::ptr::null_mut::<libc::passwd>();
+        loop {
+            let r = unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            };
+            if r != libc::ERANGE {
+                break;
+            }
+            let size = buf.len().checked_mul(2)?;
+            buf.resize(size, 0);
+        }
+        if result.is_null() {
+            return None;
+        }
+        Some(FileOwner {
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+        })
+    }
+}
+
+/// Atomically replace `path` with `contents`, with the given permissions.
+/// The file is written beside the destination and renamed into place, so a
+/// partially written file is never observed. If a `provenance` is given, it
+/// is recorded for the file. The file is left untouched if it already has
+/// the same contents and permissions. Returns whether the file was written.
+pub async fn install_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    provenance: Option<&Provenance>,
+) -> Result<bool> {
+    write_file(path, contents, mode, None, provenance).await
+}
+
+/// Atomically replace `path` with `contents`, owned by the POSIX user mapped
+/// to `account_id`, as `install_file` does. If the user has not been
+/// provisioned on this host yet (they have never been resolved via NSS),
+/// nothing is written and an error is returned, since a root owned file
+/// would be unreadable to them. The file is then installed by a later policy
+/// refresh.
+pub async fn install_user_file(
+    config: &HimmelblauConfig,
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    account_id: &str,
+    provenance: Option<&Provenance>,
+) -> Result<bool> {
+    let owner = FileOwner::for_account(config, account_id).ok_or(anyhow!(
+        "User {} is not provisioned yet, not installing {}",
+        account_id,
+        path.display()
+    ))?;
+    write_file(path, contents, mode, Some(owner), provenance).await
+}
+
+async fn write_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<FileOwner>,
+    provenance: Option<&Provenance>,
+) -> Result<bool> {
+    let changed = replace_file(path, contents, mode, owner).await?;
+    if let Some(provenance) = provenance {
+        let key = path.display().to_string();
+        let record = ProvenanceRecord {
+            policy_ids: provenance.policy_ids.clone(),
+            settings: provenance.settings.clone(),
+            applied: chrono::Utc::now().to_rfc3339(),
+        };
+        // The file is installed regardless, provenance is only for auditing
+        if let Err(e) = update_provenance(&provenance.registry, |records| {
+            // Keep the time the file was last written
+            let current = records.get(&key).is_some_and(|current| {
+                current.policy_ids == record.policy_ids && current.settings == record.settings
+            });
+            if changed || !current {
+                records.insert(key, record);
+            }
+        })
+        .await
+        {
+            warn!("Failed to record provenance of {}: {:?}", path.display(), e);
+        }
+    }
+    Ok(changed)
+}
+
+async fn replace_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<FileOwner>,
+) -> Result<bool> {
+    if let Ok(meta) = fs::metadata(path).await {
+        let same_owner = owner.map_or(true, |owner| {
+            meta.uid() == owner.uid && meta.gid() == owner.gid
+        });
+        if meta.permissions().mode() & 0o7777 == mode
+            && same_owner
+            && meta.len() == contents.len() as u64
+            && fs::read(path)
+                .await
+                .is_ok_and(|current| current == contents)
+        {
+            return Ok(false);
+        }
+    }
+
+    let dir = path
+        .parent()
+        .ok_or(anyhow!("Invalid file path {}", path.display()))?;
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("Invalid file path {}", path.display()))?;
+    fs::create_dir_all(dir)
+        .await
+        .map_err(|e| anyhow!("Failed to create directory {}: {}", dir.display(), e))?;
+
+    // The temporary file must be new, so a file or symlink planted at its
+    // path is never written through or handed to the user
+    let tmp_path = dir.join(format!(
+        ".{}.{:016x}.tmp",
+        file_name.to_string_lossy(),
+        rand::random::<u64>()
+    ));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(mode)
+        .open(&tmp_path)
+        .await
+        .map_err(|e| anyhow!("Failed to create {}: {}", tmp_path.display(), e))?;
+    let written = write_tmp_file(&mut file, &tmp_path, contents, mode, owner).await;
+    drop(file);
+    let installed = match written {
+        Ok(()) => fs::rename(&tmp_path, path)
+            .await
+            .map_err(|e| anyhow!("Failed to install {}: {}", path.display(), e)),
+        Err(e) => Err(e),
+    };
+    if installed.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    installed.map(|()| true)
+}
+
+async fn write_tmp_file(
+    file: &mut fs::File,
+    tmp_path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<FileOwner>,
+) -> Result<()> {
+    file.write_all(contents)
+        .await
+        .map_err(|e| anyhow!("Failed to write {}: {}", tmp_path.display(), e))?;
+    file.sync_all()
+        .await
+        .map_err(|e| anyhow!("Failed to sync {}: {}", tmp_path.display(), e))?;
+    // The mode passed to open() is subject to the umask
+    file.set_permissions(Permissions::from_mode(mode))
+        .await
+        .map_err(|e| anyhow!("Failed to set permissions on {}: {}", tmp_path.display(), e))?;
+    if let Some(owner) = owner {
+        if unsafe { libc::fchown(file.as_raw_fd(), owner.uid, owner.gid) } != 0 {
+            return Err(anyhow!(
+                "Failed to set ownership of {}: {}",
+                tmp_path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Remove the files in `dir` named `<prefix>*<suffix>` which are not in
+/// `keep`. Returns the removed files.
+pub async fn remove_stale_files(
+    dir: &Path,
+    prefix: &str,
+    suffix: &str,
+    keep: &HashSet<PathBuf>,
+) -> Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        // Nothing has been installed yet
+        Err(_) => return Ok(removed),
+    };
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| anyhow!("Failed to read {}: {}", dir.display(), e))?
+    {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(prefix) && name.ends_with(suffix) && !keep.contains(&path) {
+            fs::remove_file(&path)
+                .await
+                .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_install_and_remove_stale_files() {
+        let dir = std::env::temp_dir().join(format!("himmelblau-files-{}", std::process::id()));
+        let kept = dir.join("managed-a.conf");
+        let stale = dir.join("managed-b.conf");
+        let unmanaged = dir.join("other.conf");
+
+        assert!(install_file(&kept, b"a\n", 0o600, None).await.is_ok());
+        assert!(install_file(&stale, b"b\n", 0o644, None).await.is_ok());
+        assert!(install_file(&unmanaged, b"c\n", 0o644, None).await.is_ok());
+        let mode = fs::metadata(&kept)
+            .await
+            .map(|meta| meta.permissions().mode() & 0o777)
+            .ok();
+        assert_eq!(mode, Some(0o600));
+
+        // Unchanged files are not rewritten
+        assert!(matches!(
+            install_file(&kept, b"a\n", 0o600, None).await,
+            Ok(false)
+        ));
+        assert!(matches!(
+            install_file(&kept, b"a\n", 0o640, None).await,
+            Ok(true)
+        ));
+        assert!(matches!(
+            install_file(&kept, b"A\n", 0o640, None).await,
+            Ok(true)
+        ));
+
+        let keep = HashSet::from([kept.clone()]);
+        let removed = remove_stale_files(&dir, "managed-", ".conf", &keep).await;
+        assert_eq!(removed.ok(), Some(vec![stale.clone()]));
+        assert!(kept.exists());
+        assert!(!stale.exists());
+        assert!(unmanaged.exists());
+
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_install_user_file() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("himmelblau-user-files-{}", std::process::id()));
+        let path = dir.join("user.conf");
+        let owner = FileOwner {
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+        };
+
+        assert!(matches!(
+            write_file(&path, b"a\n", 0o600, Some(owner), None).await,
+            Ok(true)
+        ));
+        assert!(matches!(
+            write_file(&path, b"a\n", 0o600, Some(owner), None).await,
+            Ok(false)
+        ));
+        // Only the file itself is left behind
+        let names = std::fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name())
+                    .collect()
+            })
+            .unwrap_or(vec![]);
+        assert_eq!(names, vec![std::ffi::OsString::from("user.conf")]);
+
+        let config = HimmelblauConfig::new(None).map_err(|e| anyhow!(e))?;
+        let root = FileOwner::for_account(&config, "root");
+        assert_eq!(root, Some(FileOwner { uid: 0, gid: 0 }));
+        let missing = dir.join("missing.conf");
+        assert!(
+            install_user_file(&config, &missing, b"b\n", 0o600, "no such user", None)
+                .await
+                .is_err()
+        );
+        assert!(!missing.exists());
+
+        let _ = fs::remove_dir_all(&dir).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_provenance() {
+        let dir =
+            std::env::temp_dir().join(format!("himmelblau-provenance-{}", std::process::id()));
+        let registry = dir.join("policy_provenance.json");
+        let path = dir.join("managed.conf");
+        let provenance = Provenance {
+            registry: registry.clone(),
+            policy_ids: vec!["policy".to_string()],
+            settings: vec!["linux_test_setting".to_string()],
+        };
+
+        assert!(install_file(&path, b"a\n", 0o644, Some(&provenance))
+            .await
+            .is_ok());
+        let records = load_provenance(&registry).await.unwrap_or_default();
+        let record = records.get(&path.display().to_string());
+        assert!(record.is_some_and(|record| record.policy_ids == ["policy"]
+            && record.settings == ["linux_test_setting"]
+            && !record.applied.is_empty()));
+
+        assert!(forget_provenance(&registry, std::slice::from_ref(&path))
+            .await
+            .is_ok());
+        assert!(load_provenance(&registry)
+            .await
+            .is_ok_and(|records| records.is_empty()));
+
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+}
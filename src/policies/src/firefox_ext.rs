@@ -0,0 +1,325 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{
+    forget_provenance, install_file, load_provenance, provenance_registry, Provenance,
+};
+use crate::resolve::mark_overridden;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::IntuneStatus;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, error, warn};
+
+const SETTING_PREFIX: &str = "linux_firefox_";
+const FIREFOX_POLICIES: &str = "/etc/firefox/policies/policies.json";
+
+/// A single Firefox enterprise policy. Each linux_firefox_* setting carries
+/// one of these as a JSON document, e.g.
+/// {"policy": "DisableTelemetry", "value": true}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FirefoxSetting {
+    policy: String,
+    value: Value,
+}
+
+/// The position of a setting, by policy index and setting index.
+type Position = (usize, usize);
+
+/// The Firefox policies of all assigned policies, merged.
+struct MergedSettings {
+    /// The contents of policies.json.
+    policies: Map<String, Value>,
+    /// The positions of the settings included.
+    applied: Vec<Position>,
+    /// The settings which were overridden, with the position of the setting
+    /// which took precedence over them.
+    overridden: Vec<(Position, Vec<Position>)>,
+}
+
+/// Merge the Firefox policies of all assigned policies into the contents of
+/// policies.json. Policies are ordered by id, and when several set the same
+/// Firefox policy, object values are merged key by key, and otherwise the
+/// policy with the greatest id takes precedence. Settings which can not be
+/// parsed are added to `errors`.
+fn merge_settings(policies: &IntuneStatus, errors: &mut Vec<String>) -> MergedSettings {
+    let mut merged = Map::new();
+    // The settings included and overridden, by Firefox policy
+    let mut applied: BTreeMap<String, Vec<Position>> = BTreeMap::new();
+    let mut overridden: BTreeMap<String, Vec<Position>> = BTreeMap::new();
+    for (p, policy) in policies.policy_statuses.iter().enumerate() {
+        for (d, detail) in policy.details.iter().enumerate() {
+            if !detail
+                .setting_definition_item_id
+                .starts_with(SETTING_PREFIX)
+            {
+                continue;
+            }
+            let setting = serde_json::from_str::<FirefoxSetting>(&detail.expected_value)
+                .map_err(|e| anyhow!(e))
+                .and_then(|setting| match setting.policy.is_empty() {
+                    true => Err(anyhow!("Missing Firefox policy name")),
+                    false => Ok(setting),
+                });
+            let setting = match setting {
+                Ok(setting) => setting,
+                Err(e) => {
+                    error!(
+                        "Skipping Firefox setting {} of policy {}: {:?}",
+                        detail.setting_definition_item_id, policy.policy_id, e
+                    );
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                    continue;
+                }
+            };
+            match (merged.get_mut(&setting.policy), setting.value) {
+                (Some(Value::Object(existing)), Value::Object(value)) => existing.extend(value),
+                (existing, value) => {
+                    if existing.is_some() {
+                        warn!(
+                            "Firefox policy {} is set by multiple policies, using policy {} \
+                            and reporting the others non-compliant",
+                            setting.policy, policy.policy_id
+                        );
+                        let previous = applied.remove(&setting.policy).unwrap_or_default();
+                        overridden
+                            .entry(setting.policy.clone())
+                            .or_default()
+                            .extend(previous);
+                    }
+                    merged.insert(setting.policy.clone(), value);
+                }
+            }
+            applied.entry(setting.policy).or_default().push((p, d));
+        }
+    }
+    MergedSettings {
+        policies: merged,
+        overridden: overridden
+            .into_iter()
+            .filter_map(|(name, losers)| {
+                let winner = applied.get(&name).and_then(|settings| settings.last())?;
+                Some((*winner, losers))
+            })
+            .collect(),
+        applied: {
+            let mut applied: Vec<Position> = applied.into_values().flatten().collect();
+            applied.sort();
+            applied
+        },
+    }
+}
+
+pub struct FirefoxCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for FirefoxCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        FirefoxCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "firefox"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// The Firefox policies of all assigned policies are merged into
+    /// /etc/firefox/policies/policies.json. A policies.json which was not
+    /// installed by himmelblau (it has no provenance record) belongs to the
+    /// administrator, and is never replaced or removed.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let mut errors = vec![];
+        let MergedSettings {
+            policies: merged,
+            applied,
+            overridden,
+        } = merge_settings(policies, &mut errors);
+
+        let path = Path::new(FIREFOX_POLICIES);
+        let registry = provenance_registry(&self.config)?;
+        let ours = load_provenance(&registry)
+            .await?
+            .contains_key(&path.display().to_string());
+        let exists = path.exists();
+
+        if merged.is_empty() {
+            if exists && ours {
+                fs::remove_file(path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+                forget_provenance(&registry, &[PathBuf::from(FIREFOX_POLICIES)]).await?;
+                debug!("Removed Firefox policy {}", FIREFOX_POLICIES);
+            }
+        } else if exists && !ours {
+            return Err(anyhow!(
+                "{} was not installed by himmelblau, not replacing it",
+                FIREFOX_POLICIES
+            ));
+        } else {
+            let mut sources = BTreeSet::new();
+            let mut setting_ids = vec![];
+            for (p, d) in &applied {
+                if let Some(policy) = policies.policy_statuses.get(*p) {
+                    sources.insert(policy.policy_id.clone());
+                    if let Some(detail) = policy.details.get(*d) {
+                        setting_ids.push(detail.setting_definition_item_id.clone());
+                    }
+                }
+            }
+            let provenance =
+                Provenance::new(&self.config, sources.into_iter().collect(), setting_ids)?;
+            let contents = serde_json::to_string_pretty(&serde_json::json!({
+                "policies": merged,
+            }))?;
+            if install_file(path, contents.as_bytes(), 0o644, Some(&provenance)).await? {
+                debug!("Updated Firefox policy {}", FIREFOX_POLICIES);
+            } else {
+                debug!("Firefox policy {} is unchanged", FIREFOX_POLICIES);
+            }
+        }
+
+        for (p, d) in applied {
+            if let Some(detail) = policies
+                .policy_statuses
+                .get_mut(p)
+                .and_then(|policy| policy.details.get_mut(d))
+            {
+                detail.actual_value = detail.expected_value.clone();
+                detail.new_compliance_state = "Compliant".to_string();
+            }
+        }
+        for (winner, losers) in overridden {
+            mark_overridden(policies, winner, &losers);
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply Firefox policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_merge_settings() {
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy(
+                    "a",
+                    &[
+                        (
+                            "linux_firefox_telemetry",
+                            r#"{"policy": "DisableTelemetry", "value": true}"#,
+                        ),
+                        (
+                            "linux_firefox_homepage",
+                            r#"{"policy": "Homepage", "value": {"URL": "https://a.example.com", "Locked": true}}"#,
+                        ),
+                    ],
+                ),
+                policy(
+                    "b",
+                    &[
+                        (
+                            "linux_firefox_homepage",
+                            r#"{"policy": "Homepage", "value": {"URL": "https://b.example.com"}}"#,
+                        ),
+                        (
+                            "linux_firefox_blocked",
+                            r#"{"policy": "WebsiteFilter", "value": {"Block": ["https://*.example.org/*"]}}"#,
+                        ),
+                        ("linux_firefox_bad", r#"{"value": 1}"#),
+                    ],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let merged = merge_settings(&policies, &mut errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(merged.applied, vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+        assert!(merged.overridden.is_empty());
+        assert_eq!(
+            Value::Object(merged.policies),
+            serde_json::json!({
+                "DisableTelemetry": true,
+                "Homepage": {"URL": "https://b.example.com", "Locked": true},
+                "WebsiteFilter": {"Block": ["https://*.example.org/*"]},
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_overridden() {
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy(
+                    "a",
+                    &[(
+                        "linux_firefox_homepage",
+                        r#"{"policy": "Homepage", "value": {"URL": "https://a.example.com"}}"#,
+                    )],
+                ),
+                policy(
+                    "b",
+                    &[(
+                        "linux_firefox_homepage",
+                        r#"{"policy": "Homepage", "value": {"Locked": true}}"#,
+                    )],
+                ),
+                policy(
+                    "c",
+                    &[(
+                        "linux_firefox_homepage",
+                        r#"{"policy": "Homepage", "value": false}"#,
+                    )],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let merged = merge_settings(&policies, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(merged.applied, vec![(2, 0)]);
+        assert_eq!(merged.overridden, vec![((2, 0), vec![(0, 0), (1, 0)])]);
+        assert_eq!(
+            Value::Object(merged.policies),
+            serde_json::json!({"Homepage": false})
+        );
+    }
+}
@@ -0,0 +1,535 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::install_file;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::IntuneStatus;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, error, warn};
+
+const SETTING_PREFIX: &str = "linux_firewall_";
+// Attached to each ufw rule, so managed rules can be told apart from those
+// created by an administrator. firewalld rich rules can not carry a comment,
+// so those are only known to be managed from the state.
+const RULE_COMMENT: &str = "himmelblau";
+
+/// A single firewall rule. Each linux_firewall_* setting carries one of these
+/// as a JSON document, e.g.
+/// {"action": "allow", "port": 22, "protocol": "tcp", "source": "10.0.0.0/8"}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FirewallRule {
+    action: String,
+    port: u16,
+    protocol: String,
+    /// An address or network the traffic must originate from. By default
+    /// traffic from any source matches.
+    source: Option<String>,
+}
+
+impl FirewallRule {
+    fn validate(&self) -> Result<()> {
+        if !["allow", "deny"].contains(&self.action.as_str()) {
+            return Err(anyhow!("Invalid firewall action '{}'", self.action));
+        }
+        if !["tcp", "udp"].contains(&self.protocol.as_str()) {
+            return Err(anyhow!("Invalid firewall protocol '{}'", self.protocol));
+        }
+        if self.port == 0 {
+            return Err(anyhow!("Invalid firewall port 0"));
+        }
+        if let Some(source) = &self.source {
+            let (addr, prefix) = match source.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (source.as_str(), None),
+            };
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| anyhow!("Invalid firewall source '{}'", source))?;
+            let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+            if let Some(prefix) = prefix {
+                if prefix.parse::<u8>().map_or(true, |p| p > max_prefix) {
+                    return Err(anyhow!("Invalid firewall source '{}'", source));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The rule as ufw arguments, without the comment.
+    fn ufw(&self) -> String {
+        format!(
+            "{} proto {} from {} to any port {}",
+            self.action,
+            self.protocol,
+            self.source.as_deref().unwrap_or("any"),
+            self.port
+        )
+    }
+
+    /// The rule as a firewalld rich rule.
+    fn firewalld(&self) -> String {
+        let mut rule = String::from("rule");
+        if let Some(source) = &self.source {
+            let family = if source.contains(':') { "ipv6" } else { "ipv4" };
+            rule.push_str(&format!(
+                " family=\"{}\" source address=\"{}\"",
+                family, source
+            ));
+        }
+        rule.push_str(&format!(
+            " port port=\"{}\" protocol=\"{}\" {}",
+            self.port,
+            self.protocol,
+            if self.action == "allow" {
+                "accept"
+            } else {
+                "drop"
+            }
+        ));
+        rule
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    Ufw,
+    Firewalld,
+}
+
+impl Backend {
+    fn rule(self, rule: &FirewallRule) -> String {
+        match self {
+            Backend::Ufw => rule.ufw(),
+            Backend::Firewalld => rule.firewalld(),
+        }
+    }
+
+    /// The firewall in use on this host, if any.
+    async fn detect() -> Option<Backend> {
+        if run("firewall-cmd", &["--state"]).await.is_ok() {
+            return Some(Backend::Firewalld);
+        }
+        match Command::new("ufw").arg("status").output().await {
+            Ok(output) if String::from_utf8_lossy(&output.stdout).contains("Status: active") => {
+                Some(Backend::Ufw)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `rule` is present, whoever added it.
+    async fn exists(self, rule: &str) -> Result<bool> {
+        match self {
+            Backend::Ufw => Ok(ufw_rule(&run("ufw", &["show", "added"]).await?, rule).is_some()),
+            Backend::Firewalld => {
+                let query = format!("--query-rich-rule={}", rule);
+                let output = Command::new("firewall-cmd")
+                    .args(["--permanent", &query])
+                    .output()
+                    .await
+                    .map_err(|e| anyhow!("Failed to execute firewall-cmd: {}", e))?;
+                match output.status.code() {
+                    Some(0) => Ok(true),
+                    Some(1) => Ok(false),
+                    _ => Err(anyhow!(
+                        "firewall-cmd --permanent {} failed: {}",
+                        query,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Add `rule`, returning whether it was added. A rule which is already
+    /// present was created by an administrator, and is left to them.
+    async fn add(self, rule: &str) -> Result<bool> {
+        if self.exists(rule).await? {
+            return Ok(false);
+        }
+        match self {
+            Backend::Ufw => {
+                let mut args: Vec<&str> = rule.split(' ').collect();
+                args.extend(["comment", RULE_COMMENT]);
+                let output = run("ufw", &args).await?;
+                Ok(!output.contains("Skipping adding existing rule"))
+            }
+            Backend::Firewalld => {
+                run(
+                    "firewall-cmd",
+                    &["--permanent", &format!("--add-rich-rule={}", rule)],
+                )
+                .await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Remove a rule this extension added. `ufw delete` matches a rule
+    /// regardless of its comment, so a ufw rule is only removed while it
+    /// still carries ours.
+    async fn remove(self, rule: &str) -> Result<()> {
+        match self {
+            Backend::Ufw => {
+                if ufw_rule(&run("ufw", &["show", "added"]).await?, rule) != Some(true) {
+                    debug!(
+                        "Firewall rule {} was removed or replaced, not removing it",
+                        rule
+                    );
+                    return Ok(());
+                }
+                let mut args = vec!["delete"];
+                args.extend(rule.split(' '));
+                run("ufw", &args).await?;
+            }
+            Backend::Firewalld => {
+                run(
+                    "firewall-cmd",
+                    &["--permanent", &format!("--remove-rich-rule={}", rule)],
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find `rule` in the output of `ufw show added`, returning whether it
+/// carries the comment of the managed rules.
+fn ufw_rule(added: &str, rule: &str) -> Option<bool> {
+    let managed = format!("{} comment '{}'", rule, RULE_COMMENT);
+    added
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("ufw "))
+        .find_map(|line| {
+            if line == managed {
+                Some(true)
+            } else if line == rule || line.starts_with(&format!("{} comment ", rule)) {
+                Some(false)
+            } else {
+                None
+            }
+        })
+}
+
+/// The rules added by this extension, so that only those are ever removed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FirewallState {
+    backend: Option<Backend>,
+    rules: BTreeSet<String>,
+}
+
+/// Collect the rules of all policies for `backend`, with the policy settings
+/// (by policy index and setting index) which request each. Settings which can
+/// not be parsed are added to `errors`.
+fn resolve_rules(
+    policies: &IntuneStatus,
+    backend: Backend,
+    errors: &mut Vec<String>,
+) -> BTreeMap<String, Vec<(usize, usize)>> {
+    let mut rules: BTreeMap<String, Vec<(usize, usize)>> = BTreeMap::new();
+    for (p, policy) in policies.policy_statuses.iter().enumerate() {
+        for (d, detail) in policy.details.iter().enumerate() {
+            if !detail
+                .setting_definition_item_id
+                .starts_with(SETTING_PREFIX)
+            {
+                continue;
+            }
+            let res = serde_json::from_str::<FirewallRule>(&detail.expected_value)
+                .map_err(|e| anyhow!(e))
+                .and_then(|rule| {
+                    rule.validate()?;
+                    Ok(backend.rule(&rule))
+                });
+            match res {
+                Ok(rule) => rules.entry(rule).or_default().push((p, d)),
+                Err(e) => {
+                    error!(
+                        "Skipping firewall setting {} of policy {}: {:?}",
+                        detail.setting_definition_item_id, policy.policy_id, e
+                    );
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                }
+            }
+        }
+    }
+    rules
+}
+
+/// Run `program`, returning its output.
+async fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to execute {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub struct FirewallCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for FirewallCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        FirewallCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "firewall"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// The firewall rules of all assigned policies are added to firewalld, or
+    /// otherwise ufw, whichever is active. The rules this extension added
+    /// which are no longer assigned are removed. Rules created by an
+    /// administrator are never modified, and an assigned rule which an
+    /// administrator already created is left unmanaged.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let state_path = self.state_path()?;
+        let mut state: FirewallState = match fs::read_to_string(&state_path).await {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(_) => FirewallState::default(),
+        };
+        let assigned = policies.policy_statuses.iter().any(|policy| {
+            policy
+                .details
+                .iter()
+                .any(|d| d.setting_definition_item_id.starts_with(SETTING_PREFIX))
+        });
+        if !assigned && state.rules.is_empty() {
+            return Ok(true);
+        }
+
+        let backend = match Backend::detect().await {
+            Some(backend) => backend,
+            // With the firewall stopped, the managed rules are not in effect
+            None if !assigned => return Ok(true),
+            None => return Err(anyhow!("Neither firewalld nor ufw is active")),
+        };
+        if state.backend.is_some_and(|previous| previous != backend) {
+            warn!(
+                "The active firewall changed, rules added to {:?} are no longer managed",
+                state.backend
+            );
+            state.rules.clear();
+        }
+
+        let mut errors = vec![];
+        let rules = resolve_rules(policies, backend, &mut errors);
+        let mut changed = false;
+        let mut applied = vec![];
+
+        for (rule, settings) in &rules {
+            if !state.rules.contains(rule) {
+                match backend.add(rule).await {
+                    Ok(true) => {
+                        debug!("Added firewall rule {}", rule);
+                        state.rules.insert(rule.clone());
+                        changed = true;
+                    }
+                    Ok(false) => debug!(
+                        "Firewall rule {} was created by an administrator, not managing it",
+                        rule
+                    ),
+                    Err(e) => {
+                        errors.push(e.to_string());
+                        continue;
+                    }
+                }
+            }
+            applied.extend(settings.iter().copied());
+        }
+        let stale: Vec<String> = state
+            .rules
+            .iter()
+            .filter(|rule| !rules.contains_key(*rule))
+            .cloned()
+            .collect();
+        for rule in stale {
+            match backend.remove(&rule).await {
+                Ok(()) => {
+                    debug!("Removed firewall rule {}", rule);
+                    state.rules.remove(&rule);
+                    changed = true;
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        if changed && backend == Backend::Firewalld {
+            if let Err(e) = run("firewall-cmd", &["--reload"]).await {
+                errors.push(e.to_string());
+            }
+        }
+
+        state.backend = Some(backend);
+        install_file(
+            &state_path,
+            serde_json::to_string_pretty(&state)?.as_bytes(),
+            0o600,
+            None,
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to save firewall state: {}", e))?;
+
+        for (p, d) in applied {
+            if let Some(detail) = policies
+                .policy_statuses
+                .get_mut(p)
+                .and_then(|policy| policy.details.get_mut(d))
+            {
+                detail.actual_value = detail.expected_value.clone();
+                detail.new_compliance_state = "Compliant".to_string();
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply firewall policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+impl FirewallCSE {
+    fn state_path(&self) -> Result<PathBuf> {
+        let mut path = PathBuf::from(self.config.get_db_path());
+        if !path.pop() {
+            return Err(anyhow!("Failed to determine firewall state path"));
+        }
+        path.push("policy_firewall.json");
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    fn rule(json: &str) -> Result<FirewallRule> {
+        let rule: FirewallRule = serde_json::from_str(json)?;
+        rule.validate()?;
+        Ok(rule)
+    }
+
+    #[test]
+    fn test_firewall_rule() {
+        let ssh =
+            rule(r#"{"action": "allow", "port": 22, "protocol": "tcp", "source": "10.0.0.0/8"}"#);
+        assert!(ssh.as_ref().is_ok_and(|ssh| ssh.ufw()
+            == "allow proto tcp from 10.0.0.0/8 to any port 22"
+            && ssh.firewalld()
+                == "rule family=\"ipv4\" source address=\"10.0.0.0/8\" port port=\"22\" protocol=\"tcp\" accept"));
+        let dns = rule(r#"{"action": "deny", "port": 53, "protocol": "udp"}"#);
+        assert!(dns
+            .as_ref()
+            .is_ok_and(|dns| dns.ufw() == "deny proto udp from any to any port 53"
+                && dns.firewalld() == "rule port port=\"53\" protocol=\"udp\" drop"));
+
+        assert!(rule(r#"{"action": "reject", "port": 22, "protocol": "tcp"}"#).is_err());
+        assert!(rule(r#"{"action": "allow", "port": 22, "protocol": "icmp"}"#).is_err());
+        assert!(rule(r#"{"action": "allow", "port": 0, "protocol": "tcp"}"#).is_err());
+        assert!(rule(
+            r#"{"action": "allow", "port": 22, "protocol": "tcp", "source": "10.0.0.0/33"}"#
+        )
+        .is_err());
+        assert!(rule(
+            r#"{"action": "allow", "port": 22, "protocol": "tcp", "source": "any; rm -rf /"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ufw_rule() {
+        let added = "Added user rules (see 'ufw status' for running firewall):\n\
+            ufw allow proto tcp from any to any port 22 comment 'himmelblau'\n\
+            ufw deny proto udp from any to any port 53\n\
+            ufw allow proto tcp from any to any port 80 comment 'web'\n";
+        assert_eq!(
+            ufw_rule(added, "allow proto tcp from any to any port 22"),
+            Some(true)
+        );
+        assert_eq!(
+            ufw_rule(added, "deny proto udp from any to any port 53"),
+            Some(false)
+        );
+        assert_eq!(
+            ufw_rule(added, "allow proto tcp from any to any port 80"),
+            Some(false)
+        );
+        assert_eq!(
+            ufw_rule(added, "allow proto tcp from any to any port 8"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve_rules() {
+        let ssh = r#"{"action": "allow", "port": 22, "protocol": "tcp"}"#;
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy("a", &[("linux_firewall_ssh", ssh)]),
+                policy(
+                    "b",
+                    &[
+                        ("linux_firewall_ssh", ssh),
+                        ("linux_firewall_bad", r#"{"action": "allow"}"#),
+                    ],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let rules = resolve_rules(&policies, Backend::Ufw, &mut errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            rules,
+            BTreeMap::from([(
+                "allow proto tcp from any to any port 22".to_string(),
+                vec![(0, 0), (1, 0)]
+            )])
+        );
+    }
+}
@@ -0,0 +1,191 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Verifies that a device is able to fetch its Intune policies, without
+ * enforcing them or reporting a status back to Intune.
+ */
+use crate::breaker::{with_breaker, BreakerState};
+use crate::policies::{
+    enrolled_device, graph_for_domain, http_status, intune_user_token, policy_debug_enabled, redact,
+};
+use anyhow::Result;
+use himmelblau::error::MsalError;
+use himmelblau::intune::IntuneForLinux;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use std::time::Instant;
+use tracing::debug;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndpointStatus {
+    /// The request succeeded.
+    Ok,
+    /// The request was rejected with 401 or 403, the token lacks permission.
+    Forbidden(u16),
+    /// The endpoint responded with some other HTTP error.
+    Failed(u16),
+    /// The endpoint could not be reached, or the response was unusable.
+    Unreachable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct EndpointCheck {
+    pub endpoint: &'static str,
+    pub status: EndpointStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthReport {
+    /// Whether this device has an Intune device id for the domain.
+    pub enrolled: bool,
+    /// The endpoints checked, in the order they were contacted. Checks stop
+    /// at the first endpoint which fails.
+    pub checks: Vec<EndpointCheck>,
+    /// The number of policies assigned to this device, if they were fetched.
+    pub policy_count: Option<usize>,
+    /// The circuit breaker state of the Graph url, if it was discovered. When
+    /// open, policy refreshes enforce the last applied policies.
+    pub breaker: Option<BreakerState>,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.enrolled
+            && self.policy_count.is_some()
+            && self
+                .checks
+                .iter()
+                .all(|check| check.status == EndpointStatus::Ok)
+    }
+
+    /// The device can fetch policy, but none are assigned to it.
+    pub fn no_policies_assigned(&self) -> bool {
+        self.is_healthy() && self.policy_count == Some(0)
+    }
+
+    fn record<T>(
+        &mut self,
+        endpoint: &'static str,
+        res: Result<T, MsalError>,
+        secrets: &[&str],
+    ) -> Option<T> {
+        let (status, val) = match res {
+            Ok(val) => (EndpointStatus::Ok, Some(val)),
+            Err(e) => (endpoint_status(&e, secrets), None),
+        };
+        debug!(?endpoint, ?status, "Policy health check");
+        self.checks.push(EndpointCheck { endpoint, status });
+        val
+    }
+}
+
+/// Classify a failed request.
+fn endpoint_status(e: &MsalError, secrets: &[&str]) -> EndpointStatus {
+    match http_status(e) {
+        Some(code @ (401 | 403)) => EndpointStatus::Forbidden(code),
+        Some(code) if (400..600).contains(&code) => EndpointStatus::Failed(code),
+        _ => EndpointStatus::Unreachable(match e {
+            MsalError::RequestFailed(msg) | MsalError::GeneralFailure(msg) => redact(msg, secrets),
+            _ => redact(&format!("{:?}", e), secrets),
+        }),
+    }
+}
+
+/// Check that policies can be fetched for this device, contacting each
+/// endpoint used by apply_intune_policy once. No policies are enforced, and
+/// no status is reported to Intune. The device is looked up as
+/// apply_intune_policy does, so an enrollment with a nil device id is an
+/// error (a DeviceNotJoined) here too.
+pub async fn check_policy_access(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    graph_token: &str,
+    intune_token: &str,
+) -> Result<HealthReport> {
+    let secrets = [graph_token, intune_token];
+    let mut report = HealthReport::default();
+    let (domain, intune_device_id) =
+        match enrolled_device(config, account_id, policy_debug_enabled(config))? {
+            Some(device) => device,
+            None => return Ok(report),
+        };
+    report.enrolled = true;
+
+    let graph = match report.record(
+        "Graph discovery",
+        graph_for_domain(config, &domain).await,
+        &secrets,
+    ) {
+        Some(graph) => graph,
+        None => return Ok(report),
+    };
+    if let Ok(graph_url) = graph.graph_url().await {
+        report.breaker = Some(with_breaker(&graph_url, |breaker| {
+            breaker.state(Instant::now())
+        }));
+    }
+
+    let intune = match report.record(
+        "Intune service endpoints",
+        graph
+            .intune_service_endpoints(graph_token)
+            .await
+            .and_then(IntuneForLinux::new),
+        &secrets,
+    ) {
+        Some(intune) => intune,
+        None => return Ok(report),
+    };
+
+    let token = intune_user_token(intune_token);
+    report.policy_count = report
+        .record(
+            "Intune device policies",
+            intune.policies(&token, &intune_device_id).await,
+            &secrets,
+        )
+        .map(|policies| policies.len());
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_status() {
+        assert_eq!(
+            endpoint_status(&MsalError::RequestFailed("403 Forbidden".to_string()), &[]),
+            EndpointStatus::Forbidden(403)
+        );
+        assert_eq!(
+            endpoint_status(
+                &MsalError::GeneralFailure("500 Internal Server Error".to_string()),
+                &[]
+            ),
+            EndpointStatus::Failed(500)
+        );
+        assert!(matches!(
+            endpoint_status(
+                &MsalError::RequestFailed("reqwest::Error { kind: Request }".to_string()),
+                &[]
+            ),
+            EndpointStatus::Unreachable(_)
+        ));
+    }
+}
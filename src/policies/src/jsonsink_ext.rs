@@ -0,0 +1,157 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{forget_provenance, install_file, provenance_registry, Provenance};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::{IntuneStatus, PolicyStatus};
+use himmelblau_unix_common::config::HimmelblauConfig;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::debug;
+
+#[derive(Debug, Serialize, PartialEq)]
+struct SinkPolicy {
+    policy_id: String,
+    settings: BTreeMap<String, String>,
+}
+
+/// Collect the settings matching the filter, grouped by policy. Policies
+/// without any matching settings are omitted.
+fn matching_settings(policies: &[PolicyStatus], filter: Option<&Regex>) -> Vec<SinkPolicy> {
+    policies
+        .iter()
+        .map(|policy| SinkPolicy {
+            policy_id: policy.policy_id.clone(),
+            settings: policy
+                .details
+                .iter()
+                .filter(|detail| match filter {
+                    Some(filter) => filter.is_match(&detail.setting_definition_item_id),
+                    None => true,
+                })
+                .map(|detail| {
+                    (
+                        detail.setting_definition_item_id.clone(),
+                        detail.expected_value.clone(),
+                    )
+                })
+                .collect(),
+        })
+        .filter(|policy| !policy.settings.is_empty())
+        .collect()
+}
+
+/// Writes the assigned settings to a JSON file for enforcement by external
+/// configuration management, instead of applying them.
+pub struct JsonSinkCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for JsonSinkCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        JsonSinkCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "jsonsink"
+    }
+
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let path = match self.config.get_policy_json_sink() {
+            Some(path) => path,
+            None => return Ok(true),
+        };
+        let filter = match self.config.get_policy_json_sink_filter() {
+            Some(filter) => Some(
+                Regex::new(&filter)
+                    .map_err(|e| anyhow!("Invalid policy_json_sink_filter: {}", e))?,
+            ),
+            None => None,
+        };
+
+        let settings = matching_settings(&policies.policy_statuses, filter.as_ref());
+        if settings.is_empty() {
+            if Path::new(&path).exists() {
+                fs::remove_file(&path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to remove {}: {}", path, e))?;
+                forget_provenance(&provenance_registry(&self.config)?, &[PathBuf::from(&path)])
+                    .await?;
+                debug!("Removed policy JSON sink {}", path);
+            }
+            return Ok(true);
+        }
+        let data = serde_json::to_string_pretty(&settings)?;
+        let provenance = Provenance::new(
+            &self.config,
+            settings
+                .iter()
+                .map(|policy| policy.policy_id.clone())
+                .collect(),
+            settings
+                .iter()
+                .flat_map(|policy| policy.settings.keys().cloned())
+                .collect(),
+        )?;
+        if install_file(Path::new(&path), data.as_bytes(), 0o600, Some(&provenance)).await? {
+            debug!("Wrote {} policies to JSON sink {}", settings.len(), path);
+        } else {
+            debug!("Policy JSON sink {} is unchanged", path);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_matching_settings() {
+        let policies = vec![
+            policy("a", &[("linux_customconfig_script", "ZWNobw==")]),
+            policy(
+                "b",
+                &[
+                    ("linux_mount_where", "/mnt/dept"),
+                    ("linux_mount_type", "nfs"),
+                ],
+            ),
+        ];
+        let filter = Regex::new("^linux_mount_").ok();
+        assert_eq!(
+            matching_settings(&policies, filter.as_ref()),
+            vec![SinkPolicy {
+                policy_id: "b".to_string(),
+                settings: BTreeMap::from([
+                    ("linux_mount_type".to_string(), "nfs".to_string()),
+                    ("linux_mount_where".to_string(), "/mnt/dept".to_string()),
+                ]),
+            }]
+        );
+        assert_eq!(matching_settings(&policies, None).len(), 2);
+    }
+}
@@ -33,6 +33,27 @@ pub mod policies;
 #[cfg(target_family = "unix")]
 pub mod cse;
 
+#[cfg(target_family = "unix")]
+pub mod snapshot;
+
+#[cfg(target_family = "unix")]
+pub mod health;
+
+#[cfg(target_family = "unix")]
+pub mod files;
+
+#[cfg(target_family = "unix")]
+pub mod status_file;
+
+#[cfg(target_family = "unix")]
+pub mod breaker;
+
+#[cfg(target_family = "unix")]
+pub mod audit;
+
+#[cfg(target_family = "unix")]
+pub mod resolve;
+
 /* The following are Client Side Extensions for applying policy to the host.
  * Make sure these are added to policies::apply_group_policy().
  */
@@ -42,3 +63,30 @@ pub mod scripts_ext;
 
 #[cfg(target_family = "unix")]
 pub mod compliance_ext;
+
+#[cfg(target_family = "unix")]
+pub mod networkmanager_ext;
+
+#[cfg(target_family = "unix")]
+pub mod mount_ext;
+
+#[cfg(target_family = "unix")]
+pub mod dconf_ext;
+
+#[cfg(target_family = "unix")]
+pub mod jsonsink_ext;
+
+#[cfg(target_family = "unix")]
+pub mod sudoers_ext;
+
+#[cfg(target_family = "unix")]
+pub mod sshd_ext;
+
+#[cfg(target_family = "unix")]
+pub mod firewall_ext;
+
+#[cfg(target_family = "unix")]
+pub mod firefox_ext;
+
+#[cfg(target_family = "unix")]
+pub mod sysctl_ext;
@@ -0,0 +1,562 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{
+    forget_provenance, install_file, load_provenance, provenance_registry, Provenance,
+    ProvenanceRecord,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::{IntuneStatus, PolicyStatus};
+use himmelblau_unix_common::config::HimmelblauConfig;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, error, warn};
+
+const SETTING_PREFIX: &str = "linux_mount_";
+const UNIT_DIR: &str = "/etc/systemd/system";
+const SUPPORTED_TYPES: &[&str] = &["nfs", "nfs4", "cifs", "smb3"];
+
+/// Escape a mount point into a systemd unit name, as
+/// `systemd-escape --path` does.
+fn escape_unit_path(path: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".to_string();
+    }
+    let mut escaped = String::new();
+    for (i, component) in trimmed.split('/').filter(|c| !c.is_empty()).enumerate() {
+        if i > 0 {
+            escaped.push('-');
+        }
+        for b in component.bytes() {
+            // Only a '.' leading the whole name is escaped
+            if b.is_ascii_alphanumeric()
+                || b == b':'
+                || b == b'_'
+                || (b == b'.' && !escaped.is_empty())
+            {
+                escaped.push(b as char);
+            } else {
+                escaped.push_str(&format!("\\x{:02x}", b));
+            }
+        }
+    }
+    escaped
+}
+
+struct MountUnit {
+    /// The unit name, without the .mount/.automount suffix.
+    name: String,
+    mount: String,
+    automount: Option<String>,
+}
+
+/// Render the mount (and optional automount) units described by a policy.
+fn render_mount(policy: &PolicyStatus) -> Result<MountUnit> {
+    let settings: BTreeMap<&str, &str> = policy
+        .details
+        .iter()
+        .filter_map(|detail| {
+            detail
+                .setting_definition_item_id
+                .strip_prefix(SETTING_PREFIX)
+                .map(|key| (key, detail.expected_value.trim()))
+        })
+        .collect();
+    let required = |key: &str| {
+        settings
+            .get(key)
+            .copied()
+            .filter(|val| !val.is_empty())
+            .ok_or(anyhow!(
+                "Missing required setting {}{}",
+                SETTING_PREFIX,
+                key
+            ))
+    };
+
+    let what = required("what")?;
+    let where_ = required("where")?;
+    let fs_type = required("type")?;
+    let options = settings.get("options").copied().unwrap_or("");
+    let automount = match settings.get("automount").copied().unwrap_or("false") {
+        "true" => true,
+        "false" => false,
+        val => return Err(anyhow!("Invalid automount value '{}'", val)),
+    };
+
+    let where_path = Path::new(where_);
+    if !where_path.is_absolute()
+        || where_path.parent().is_none()
+        || where_path
+            .components()
+            .any(|c| matches!(c, Component::ParentDir | Component::CurDir))
+    {
+        return Err(anyhow!("Invalid mount point '{}'", where_));
+    }
+    if !SUPPORTED_TYPES.contains(&fs_type) {
+        return Err(anyhow!("Unsupported file system type '{}'", fs_type));
+    }
+    if [what, where_, options]
+        .iter()
+        .any(|val| val.contains(['\n', '\r']))
+    {
+        return Err(anyhow!("Invalid mount setting for '{}'", where_));
+    }
+
+    let mut mount = format!(
+        "# Managed by himmelblau, policy {}\n\
+        [Unit]\n\
+        Description=Intune managed mount of {}\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Mount]\n\
+        What={}\n\
+        Where={}\n\
+        Type={}\n",
+        policy.policy_id, what, what, where_, fs_type
+    );
+    if !options.is_empty() {
+        mount.push_str(&format!("Options={}\n", options));
+    }
+    if !automount {
+        mount.push_str("\n[Install]\nWantedBy=remote-fs.target\n");
+    }
+    let automount = automount.then(|| {
+        format!(
+            "# Managed by himmelblau, policy {}\n\
+            [Unit]\n\
+            Description=Intune managed automount of {}\n\
+            \n\
+            [Automount]\n\
+            Where={}\n\
+            \n\
+            [Install]\n\
+            WantedBy=remote-fs.target\n",
+            policy.policy_id, what, where_
+        )
+    });
+
+    Ok(MountUnit {
+        name: escape_unit_path(where_),
+        mount,
+        automount,
+    })
+}
+
+/// The id of the policy which manages each mount point, by unit name.
+/// Policies are ordered by id, and when several mount the same mount point,
+/// the policy with the greatest id takes precedence.
+fn mount_owners(policies: &IntuneStatus) -> BTreeMap<String, String> {
+    policies
+        .policy_statuses
+        .iter()
+        .filter_map(|policy| {
+            render_mount(policy)
+                .ok()
+                .map(|unit| (unit.name, policy.policy_id.clone()))
+        })
+        .collect()
+}
+
+/// Report the mount settings of the policy at index `p` non-compliant, with
+/// the values of the same settings of the `owner` policy as their actual
+/// values.
+fn mark_overridden(policies: &mut IntuneStatus, p: usize, owner: &str) {
+    let actual: BTreeMap<String, String> = policies
+        .policy_statuses
+        .iter()
+        .find(|policy| policy.policy_id == owner)
+        .map(|policy| {
+            policy
+                .details
+                .iter()
+                .map(|d| {
+                    (
+                        d.setting_definition_item_id.clone(),
+                        d.expected_value.clone(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(policy) = policies.policy_statuses.get_mut(p) {
+        for detail in policy.details.iter_mut() {
+            if detail
+                .setting_definition_item_id
+                .starts_with(SETTING_PREFIX)
+            {
+                detail.actual_value = actual
+                    .get(&detail.setting_definition_item_id)
+                    .cloned()
+                    .unwrap_or_default();
+                detail.new_compliance_state = "NonCompliant".to_string();
+            }
+        }
+    }
+}
+
+async fn systemctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("systemctl")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to execute systemctl: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "systemctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+pub struct MountCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for MountCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        MountCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "mounts"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// Each mount policy describes a single network share, which is mounted
+    /// by a managed systemd mount unit (and optionally an automount unit).
+    /// The units of policies which are no longer assigned are removed, while
+    /// a policy which fails to apply keeps its previous units. When several
+    /// policies mount the same mount point, only the policy which takes
+    /// precedence is applied. Units which were not installed by himmelblau
+    /// are never replaced.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let state_path = self.state_path()?;
+        // The policy which installed each unit, by unit name
+        let previous: BTreeMap<String, String> = match fs::read_to_string(&state_path).await {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(_) => BTreeMap::new(),
+        };
+        let registry = provenance_registry(&self.config)?;
+        let records = load_provenance(&registry).await?;
+        let mut managed = BTreeMap::new();
+        let mut enable = vec![];
+        let mut changed = false;
+        let mut errors = vec![];
+        let owners = mount_owners(policies);
+        // The policies which lost a mount point to another, by index
+        let mut overridden = vec![];
+
+        for (p, policy) in policies.policy_statuses.iter_mut().enumerate() {
+            // Validate this is a mount policy
+            if !policy
+                .details
+                .iter()
+                .any(|d| d.setting_definition_item_id.starts_with(SETTING_PREFIX))
+            {
+                continue;
+            }
+            if let Ok(unit) = render_mount(policy) {
+                if let Some(owner) = owners.get(&unit.name).filter(|o| **o != policy.policy_id) {
+                    warn!(
+                        "Mount point {} is set by policies {} and {}, using policy {} and \
+                        reporting the settings of policy {} non-compliant",
+                        unit.name, policy.policy_id, owner, owner, policy.policy_id
+                    );
+                    overridden.push((p, owner.clone()));
+                    continue;
+                }
+            }
+            match self.install_units(policy, &records).await {
+                Ok((name, unit, updated)) => {
+                    for detail in policy.details.iter_mut() {
+                        if detail
+                            .setting_definition_item_id
+                            .starts_with(SETTING_PREFIX)
+                        {
+                            detail.actual_value = detail.expected_value.clone();
+                            detail.new_compliance_state = "Compliant".to_string();
+                        }
+                    }
+                    // Units are only (re)started when they change
+                    if updated || !previous.contains_key(&name) {
+                        enable.push(unit);
+                    }
+                    managed.insert(name, policy.policy_id.clone());
+                    changed |= updated;
+                }
+                Err(e) => {
+                    error!("Skipping mount policy {}: {:?}", policy.policy_id, e);
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                    // Keep the units the policy previously installed
+                    for (name, owner) in &previous {
+                        if *owner == policy.policy_id {
+                            managed.insert(name.clone(), owner.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        for (p, owner) in overridden {
+            mark_overridden(policies, p, &owner);
+        }
+
+        // Remove the units of mounts which are no longer assigned
+        let mut removed = vec![];
+        for name in previous.keys().filter(|name| !managed.contains_key(*name)) {
+            for unit in [format!("{}.automount", name), format!("{}.mount", name)] {
+                let path = Path::new(UNIT_DIR).join(&unit);
+                if path.exists() {
+                    if let Err(e) = systemctl(&["disable", "--now", &unit]).await {
+                        errors.push(e.to_string());
+                    }
+                    let _ = fs::remove_file(&path).await;
+                    debug!("Removed mount unit {}", path.display());
+                    removed.push(path);
+                }
+            }
+        }
+
+        forget_provenance(&registry, &removed).await?;
+
+        if changed || !removed.is_empty() {
+            systemctl(&["daemon-reload"]).await?;
+        }
+        for unit in &enable {
+            if let Err(e) = systemctl(&["enable", "--now", unit]).await {
+                errors.push(e.to_string());
+            }
+        }
+
+        install_file(
+            &state_path,
+            serde_json::to_string_pretty(&managed)?.as_bytes(),
+            0o600,
+            None,
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to save mount state: {}", e))?;
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply mount policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+impl MountCSE {
+    fn state_path(&self) -> Result<PathBuf> {
+        let mut path = PathBuf::from(self.config.get_db_path());
+        if !path.pop() {
+            return Err(anyhow!("Failed to determine mount state path"));
+        }
+        path.push("policy_mounts.json");
+        Ok(path)
+    }
+
+    /// Install the units for a policy, returning the unit name, the unit
+    /// which should be enabled, and whether any unit was changed. Units which
+    /// exist without a provenance record belong to the administrator, and
+    /// are refused.
+    async fn install_units(
+        &self,
+        policy: &PolicyStatus,
+        records: &BTreeMap<String, ProvenanceRecord>,
+    ) -> Result<(String, String, bool)> {
+        let unit = render_mount(policy)?;
+        for suffix in ["mount", "automount"] {
+            let path = Path::new(UNIT_DIR).join(format!("{}.{}", unit.name, suffix));
+            if path.exists() && !records.contains_key(&path.display().to_string()) {
+                return Err(anyhow!(
+                    "{} was not installed by himmelblau, not replacing it",
+                    path.display()
+                ));
+            }
+        }
+        let provenance = Provenance::for_policy(&self.config, policy, SETTING_PREFIX)?;
+        let mount_path = Path::new(UNIT_DIR).join(format!("{}.mount", unit.name));
+        let mut changed =
+            install_file(&mount_path, unit.mount.as_bytes(), 0o644, Some(&provenance)).await?;
+        let enable = match &unit.automount {
+            Some(automount) => {
+                let automount_path = Path::new(UNIT_DIR).join(format!("{}.automount", unit.name));
+                changed |= install_file(
+                    &automount_path,
+                    automount.as_bytes(),
+                    0o644,
+                    Some(&provenance),
+                )
+                .await?;
+                format!("{}.automount", unit.name)
+            }
+            None => {
+                // The mount may previously have been automounted
+                let automount = format!("{}.automount", unit.name);
+                let automount_path = Path::new(UNIT_DIR).join(&automount);
+                if automount_path.exists() {
+                    systemctl(&["disable", "--now", &automount]).await?;
+                    fs::remove_file(&automount_path).await?;
+                    forget_provenance(&provenance_registry(&self.config)?, &[automount_path])
+                        .await?;
+                    changed = true;
+                }
+                format!("{}.mount", unit.name)
+            }
+        };
+        if changed {
+            debug!("Updated mount units for {}", unit.name);
+        } else {
+            debug!("Mount units for {} are unchanged", unit.name);
+        }
+        Ok((unit.name, enable, changed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_escape_unit_path() {
+        assert_eq!(escape_unit_path("/mnt/share"), "mnt-share");
+        assert_eq!(escape_unit_path("/srv//dept share/"), "srv-dept\\x20share");
+        assert_eq!(escape_unit_path("/mnt/.hidden"), "mnt-.hidden");
+        assert_eq!(escape_unit_path("/.snapshots/a"), "\\x2esnapshots-a");
+        assert_eq!(escape_unit_path("/mnt/my-share"), "mnt-my\\x2dshare");
+    }
+
+    #[test]
+    fn test_render_mount() {
+        let unit = render_mount(&policy(
+            "policy",
+            &[
+                ("linux_mount_what", "//files.example.com/dept"),
+                ("linux_mount_where", "/mnt/dept"),
+                ("linux_mount_type", "cifs"),
+                ("linux_mount_options", "sec=krb5,multiuser"),
+                ("linux_mount_automount", "true"),
+            ],
+        ));
+        assert!(unit.is_ok_and(|unit| unit.name == "mnt-dept"
+            && unit.mount.contains("Options=sec=krb5,multiuser\n")
+            && !unit.mount.contains("[Install]")
+            && unit
+                .automount
+                .is_some_and(|automount| automount.contains("Where=/mnt/dept\n"))));
+
+        assert!(render_mount(&policy(
+            "policy",
+            &[
+                ("linux_mount_what", "nfs:/export"),
+                ("linux_mount_type", "nfs")
+            ]
+        ))
+        .is_err());
+        assert!(render_mount(&policy(
+            "policy",
+            &[
+                ("linux_mount_what", "nfs:/export"),
+                ("linux_mount_where", "/mnt/../etc"),
+                ("linux_mount_type", "nfs"),
+            ]
+        ))
+        .is_err());
+        assert!(render_mount(&policy(
+            "policy",
+            &[
+                ("linux_mount_what", "nfs:/export"),
+                ("linux_mount_where", "/mnt/export"),
+                ("linux_mount_type", "ext4"),
+            ]
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_mount_owners() {
+        let mount = |policy_id: &str, what: &str, where_: &str| {
+            policy(
+                policy_id,
+                &[
+                    ("linux_mount_what", what),
+                    ("linux_mount_where", where_),
+                    ("linux_mount_type", "nfs"),
+                ],
+            )
+        };
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                mount("a", "nfs:/export/a", "/mnt/share"),
+                mount("b", "nfs:/export/b", "/mnt/share"),
+                mount("c", "nfs:/export/c", "/mnt/other"),
+                policy(
+                    "d",
+                    &[
+                        ("linux_mount_what", "nfs:/export/d"),
+                        ("linux_mount_where", "/mnt/other"),
+                    ],
+                ),
+            ],
+        };
+        assert_eq!(
+            mount_owners(&policies),
+            BTreeMap::from([
+                ("mnt-share".to_string(), "b".to_string()),
+                ("mnt-other".to_string(), "c".to_string()),
+            ])
+        );
+
+        let mut policies = policies;
+        mark_overridden(&mut policies, 0, "b");
+        assert_eq!(
+            policies
+                .policy_statuses
+                .first()
+                .map(|policy| policy
+                    .details
+                    .iter()
+                    .map(|d| (d.actual_value.as_str(), d.new_compliance_state.as_str()))
+                    .collect::<Vec<_>>())
+                .unwrap_or_default(),
+            vec![
+                ("nfs:/export/b", "NonCompliant"),
+                ("/mnt/share", "NonCompliant"),
+                ("nfs", "NonCompliant"),
+            ]
+        );
+    }
+}
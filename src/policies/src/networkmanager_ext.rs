@@ -0,0 +1,465 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{
+    forget_provenance, install_file, provenance_registry, remove_stale_files, Provenance,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::{IntuneStatus, PolicyStatus};
+use himmelblau_unix_common::config::HimmelblauConfig;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::{debug, error};
+
+const SETTING_PREFIX: &str = "linux_networkmanager_";
+const CONNECTIONS_DIR: &str = "/etc/NetworkManager/system-connections";
+const CONNECTION_FILE_PREFIX: &str = "himmelblau-";
+const CONNECTION_FILE_SUFFIX: &str = ".nmconnection";
+
+/// A NetworkManager keyfile, as an ordered list of sections.
+#[derive(Default)]
+struct KeyFile {
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl KeyFile {
+    fn section(&mut self, name: &str, entries: &[(&str, &str)]) -> Result<()> {
+        let mut section = vec![];
+        for (key, value) in entries {
+            if key.is_empty()
+                || key
+                    .chars()
+                    .any(|c| c.is_control() || "=[]#".contains(c) || c.is_whitespace())
+            {
+                return Err(anyhow!("Invalid key '{}' in '{}'", key, name));
+            }
+            // Keyfile values are single lines
+            if value.contains(['\n', '\r']) {
+                return Err(anyhow!("Invalid value for '{}.{}'", name, key));
+            }
+            let value = match (name, *key) {
+                ("wifi", "ssid") => escape_ssid(value),
+                _ => escape(value),
+            };
+            section.push((key.to_string(), value));
+        }
+        self.sections.push((name.to_string(), section));
+        Ok(())
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, entries) in &self.sections {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("[{}]\n", name));
+            for (key, value) in entries {
+                out.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+        out
+    }
+}
+
+/// Escape a keyfile string value, as GLib's g_key_file_set_string() does.
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            ' ' if i == 0 => escaped.push_str("\\s"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escape an SSID, as NetworkManager does. A printable SSID is written as a
+/// string with its semicolons escaped, and any other as a list of bytes.
+fn escape_ssid(ssid: &str) -> String {
+    if ssid.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+        escape(&ssid.replace(';', "\\;"))
+    } else {
+        ssid.bytes().map(|b| format!("{};", b)).collect()
+    }
+}
+
+/// Parse a comma separated list of key=value pairs. As in nmcli, a ',' (or
+/// '\') within a value is escaped with a '\'.
+fn parse_pairs(value: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = vec![];
+    let mut pair = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ (',' | '\\')) => pair.push(c),
+                _ => return Err(anyhow!("Invalid escape in '{}'", value)),
+            },
+            ',' => pairs.push(std::mem::take(&mut pair)),
+            c => pair.push(c),
+        }
+    }
+    pairs.push(pair);
+    pairs
+        .iter()
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .ok_or(anyhow!("Invalid key=value pair '{}'", pair))
+        })
+        .collect()
+}
+
+/// Render the connection described by a policy's settings as a keyfile.
+fn render_connection(policy: &PolicyStatus) -> Result<String> {
+    let settings: BTreeMap<&str, &str> = policy
+        .details
+        .iter()
+        .filter_map(|detail| {
+            detail
+                .setting_definition_item_id
+                .strip_prefix(SETTING_PREFIX)
+                .map(|key| (key, detail.expected_value.as_str()))
+        })
+        .collect();
+    let required = |key: &str| {
+        settings
+            .get(key)
+            .copied()
+            .filter(|val| !val.is_empty())
+            .ok_or(anyhow!(
+                "Missing required setting {}{}",
+                SETTING_PREFIX,
+                key
+            ))
+    };
+
+    let name = required("name")?;
+    let conn_type = required("type")?;
+    let autoconnect = settings.get("autoconnect").copied().unwrap_or("true");
+    let mut keyfile = KeyFile::default();
+
+    match conn_type {
+        "wifi" => {
+            keyfile.section(
+                "connection",
+                &[("id", name), ("type", "wifi"), ("autoconnect", autoconnect)],
+            )?;
+            keyfile.section(
+                "wifi",
+                &[("mode", "infrastructure"), ("ssid", required("wifi_ssid")?)],
+            )?;
+            match settings.get("wifi_security").copied().unwrap_or("none") {
+                "none" => {}
+                "wpa-psk" => {
+                    keyfile.section(
+                        "wifi-security",
+                        &[("key-mgmt", "wpa-psk"), ("psk", required("wifi_psk")?)],
+                    )?;
+                }
+                "wpa-eap" => {
+                    keyfile.section("wifi-security", &[("key-mgmt", "wpa-eap")])?;
+                    let eap = required("wifi_eap")?;
+                    let mut entries = vec![("eap", eap), ("identity", required("wifi_identity")?)];
+                    match eap {
+                        "peap" | "ttls" => {
+                            entries.push(("password", required("wifi_password")?));
+                            entries.push((
+                                "phase2-auth",
+                                settings
+                                    .get("wifi_phase2auth")
+                                    .copied()
+                                    .unwrap_or("mschapv2"),
+                            ));
+                        }
+                        "tls" => {
+                            entries.push(("client-cert", required("wifi_clientcert")?));
+                            entries.push(("private-key", required("wifi_privatekey")?));
+                        }
+                        _ => return Err(anyhow!("Unsupported EAP method '{}'", eap)),
+                    }
+                    if let Some(ca_cert) = settings.get("wifi_cacert") {
+                        entries.push(("ca-cert", ca_cert));
+                    }
+                    keyfile.section("802-1x", &entries)?;
+                }
+                security => {
+                    return Err(anyhow!("Unsupported Wi-Fi security '{}'", security));
+                }
+            }
+        }
+        "vpn" => {
+            keyfile.section(
+                "connection",
+                &[("id", name), ("type", "vpn"), ("autoconnect", autoconnect)],
+            )?;
+            let data = parse_pairs(settings.get("vpn_data").copied().unwrap_or(""))?;
+            let mut entries = vec![("service-type", required("vpn_servicetype")?)];
+            entries.extend(data.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            keyfile.section("vpn", &entries)?;
+            let secrets = parse_pairs(settings.get("vpn_secrets").copied().unwrap_or(""))?;
+            if !secrets.is_empty() {
+                let entries: Vec<(&str, &str)> = secrets
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                keyfile.section("vpn-secrets", &entries)?;
+            }
+        }
+        _ => return Err(anyhow!("Unsupported connection type '{}'", conn_type)),
+    }
+    keyfile.section("ipv4", &[("method", "auto")])?;
+    keyfile.section("ipv6", &[("method", "auto")])?;
+
+    Ok(keyfile.render())
+}
+
+fn connection_path(policy_id: &str) -> Result<PathBuf> {
+    if policy_id.is_empty() || policy_id.contains('/') {
+        return Err(anyhow!("Invalid policy id '{}'", policy_id));
+    }
+    Ok(Path::new(CONNECTIONS_DIR).join(format!(
+        "{}{}{}",
+        CONNECTION_FILE_PREFIX, policy_id, CONNECTION_FILE_SUFFIX
+    )))
+}
+
+pub struct NetworkManagerCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for NetworkManagerCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        NetworkManagerCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "networkmanager"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// Each NetworkManager policy describes a single Wi-Fi or VPN connection,
+    /// which is written to a managed keyfile. Keyfiles of policies which are
+    /// no longer assigned are removed.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let mut managed = HashSet::new();
+        let mut changed = false;
+        let mut errors = vec![];
+
+        for policy in policies.policy_statuses.iter_mut() {
+            // Validate this is a NetworkManager policy
+            if !policy
+                .details
+                .iter()
+                .any(|d| d.setting_definition_item_id.starts_with(SETTING_PREFIX))
+            {
+                continue;
+            }
+            let path = match connection_path(&policy.policy_id) {
+                Ok(path) => path,
+                Err(e) => {
+                    errors.push(e.to_string());
+                    continue;
+                }
+            };
+            // Keep a previously installed connection if this one fails
+            managed.insert(path.clone());
+            let res = match render_connection(policy).and_then(|keyfile| {
+                Ok((
+                    keyfile,
+                    Provenance::for_policy(&self.config, policy, SETTING_PREFIX)?,
+                ))
+            }) {
+                Ok((keyfile, provenance)) => {
+                    install_file(&path, keyfile.as_bytes(), 0o600, Some(&provenance)).await
+                }
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(updated) => {
+                    for detail in policy.details.iter_mut() {
+                        if detail
+                            .setting_definition_item_id
+                            .starts_with(SETTING_PREFIX)
+                        {
+                            detail.actual_value = detail.expected_value.clone();
+                            detail.new_compliance_state = "Compliant".to_string();
+                        }
+                    }
+                    if updated {
+                        debug!(
+                            "Updated NetworkManager connection {} for policy {}",
+                            path.display(),
+                            policy.policy_id
+                        );
+                    } else {
+                        debug!(
+                            "NetworkManager connection {} for policy {} is unchanged",
+                            path.display(),
+                            policy.policy_id
+                        );
+                    }
+                    changed |= updated;
+                }
+                Err(e) => {
+                    error!(
+                        "Skipping NetworkManager policy {}: {:?}",
+                        policy.policy_id, e
+                    );
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                }
+            }
+        }
+
+        let removed = remove_stale_files(
+            Path::new(CONNECTIONS_DIR),
+            CONNECTION_FILE_PREFIX,
+            CONNECTION_FILE_SUFFIX,
+            &managed,
+        )
+        .await?;
+        for path in &removed {
+            debug!("Removed NetworkManager connection {}", path.display());
+        }
+        forget_provenance(&provenance_registry(&self.config)?, &removed).await?;
+
+        if changed || !removed.is_empty() {
+            let output = Command::new("nmcli")
+                .args(["connection", "reload"])
+                .output()
+                .await
+                .map_err(|e| anyhow!("Failed to execute nmcli: {}", e))?;
+            if !output.status.success() {
+                errors.push(format!(
+                    "Failed to reload NetworkManager connections: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply NetworkManager policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_render_connection() {
+        let keyfile = render_connection(&policy(
+            "policy",
+            &[
+                ("linux_networkmanager_name", "Corp"),
+                ("linux_networkmanager_type", "wifi"),
+                ("linux_networkmanager_wifi_ssid", "corp-wifi"),
+                ("linux_networkmanager_wifi_security", "wpa-eap"),
+                ("linux_networkmanager_wifi_eap", "peap"),
+                ("linux_networkmanager_wifi_identity", "tux@example.com"),
+                ("linux_networkmanager_wifi_password", "secret"),
+            ],
+        ));
+        assert_eq!(
+            keyfile.ok().as_deref(),
+            Some(
+                "[connection]\nid=Corp\ntype=wifi\nautoconnect=true\n\n\
+                [wifi]\nmode=infrastructure\nssid=corp-wifi\n\n\
+                [wifi-security]\nkey-mgmt=wpa-eap\n\n\
+                [802-1x]\neap=peap\nidentity=tux@example.com\npassword=secret\nphase2-auth=mschapv2\n\n\
+                [ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n"
+            )
+        );
+
+        let keyfile = render_connection(&policy(
+            "policy",
+            &[
+                ("linux_networkmanager_name", "Corp VPN"),
+                ("linux_networkmanager_type", "vpn"),
+                (
+                    "linux_networkmanager_vpn_servicetype",
+                    "org.freedesktop.NetworkManager.openvpn",
+                ),
+                (
+                    "linux_networkmanager_vpn_data",
+                    "remote=vpn.example.com, connection-type=tls",
+                ),
+            ],
+        ));
+        assert!(keyfile.is_ok_and(|keyfile| keyfile.contains(
+            "[vpn]\nservice-type=org.freedesktop.NetworkManager.openvpn\n\
+            remote=vpn.example.com\nconnection-type=tls\n"
+        )));
+
+        assert!(render_connection(&policy(
+            "policy",
+            &[
+                ("linux_networkmanager_name", "Corp"),
+                ("linux_networkmanager_type", "wifi")
+            ]
+        ))
+        .is_err());
+        assert!(render_connection(&policy(
+            "policy",
+            &[
+                ("linux_networkmanager_name", "Corp\n[vpn]"),
+                ("linux_networkmanager_type", "wifi"),
+                ("linux_networkmanager_wifi_ssid", "corp-wifi"),
+            ]
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        assert_eq!(
+            parse_pairs(r"user=tux, password=a\,b\\c,").ok(),
+            Some(vec![
+                ("user".to_string(), "tux".to_string()),
+                ("password".to_string(), r"a,b\c".to_string()),
+            ])
+        );
+        assert!(parse_pairs("user").is_err());
+        assert!(parse_pairs(r"password=a\b").is_err());
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(" a\\b\tc"), r"\sa\\b\tc");
+        assert_eq!(escape_ssid("corp;wifi"), r"corp\\;wifi");
+        assert_eq!(escape_ssid("caf\u{e9}"), "99;97;102;195;169;");
+    }
+}
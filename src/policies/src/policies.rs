@@ -15,115 +15,1917 @@
    You should have received a copy of the GNU General Public License
    along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
+use crate::audit::write_audit;
+use crate::breaker::with_breaker;
 use crate::compliance_ext::ComplianceCSE;
-use crate::cse::CSE;
+use crate::cse::{order_extensions, CSE};
+use crate::dconf_ext::DconfCSE;
+use crate::firefox_ext::FirefoxCSE;
+use crate::firewall_ext::FirewallCSE;
+use crate::jsonsink_ext::JsonSinkCSE;
+use crate::mount_ext::MountCSE;
+use crate::networkmanager_ext::NetworkManagerCSE;
 use crate::scripts_ext::ScriptsCSE;
+use crate::snapshot::{
+    diff_last_applied, diff_snapshots, restore_compliance, sort_policies, FileSnapshotCache,
+    PolicyDiff, SnapshotCache,
+};
+use crate::sshd_ext::SshdCSE;
+use crate::status_file::{record_run, PolicyRunStatus};
+use crate::sudoers_ext::SudoersCSE;
+use crate::sysctl_ext::SysctlCSE;
 use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use himmelblau::error::MsalError;
 use himmelblau::graph::Graph;
 use himmelblau::intune::{IntuneForLinux, IntuneStatus};
 use himmelblau::{ClientInfo, EnrollAttrs, IdToken, UserToken};
-use himmelblau_unix_common::config::{split_username, HimmelblauConfig};
-use std::sync::Arc;
-use tracing::{debug, instrument};
+use himmelblau_unix_common::config::{
+    split_username, HimmelblauConfig, PolicyHttpConfig, PolicyLimits,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
 
-#[instrument(skip(config, graph_token, intune_token))]
+/// The device has an Intune device id which is empty or a placeholder, for
+/// instance while the join is still in progress. Policy should be deferred
+/// until enrollment completes.
+#[derive(Debug)]
+pub struct DeviceNotJoined(pub String);
+
+impl fmt::Display for DeviceNotJoined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Device is not joined, invalid Intune device id '{}'",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for DeviceNotJoined {}
+
+/// The policy refresh was stopped at the policy_refresh_deadline. The next
+/// refresh resumes after the extensions which completed.
+#[derive(Debug)]
+pub struct DeadlineExceeded(pub Duration);
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Policy refresh exceeded its deadline of {} seconds",
+            self.0.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Bound the total time of a refresh. Dropping the refresh at the deadline is
+/// safe, the apply progress is recorded as each extension completes.
+async fn with_deadline<T>(
+    deadline: Option<Duration>,
+    refresh: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) => match tokio::time::timeout(deadline, refresh).await {
+            Ok(res) => res,
+            Err(_) => Err(DeadlineExceeded(deadline).into()),
+        },
+        None => refresh.await,
+    }
+}
+
+/* Graph permissions, any one of which is sufficient to read the Intune
+ * service endpoints (GET /servicePrincipals/appId=.../endpoints). The Intune
+ * check-in service itself is not part of Graph, and only requires a token for
+ * the resource 0000000a-0000-0000-c000-000000000000.
+ */
+const INTUNE_ENDPOINTS_SCOPES: &[&str] = &[
+    "Application.Read.All",
+    "Application.ReadWrite.All",
+    "Directory.Read.All",
+    "Directory.ReadWrite.All",
+];
+
+/// Wrap an access token for the Intune resource in the UserToken expected by
+/// IntuneForLinux.
+pub(crate) fn intune_user_token(intune_token: &str) -> UserToken {
+    UserToken {
+        token_type: String::new(),
+        scope: None,
+        expires_in: 0,
+        ext_expires_in: 0,
+        refresh_token: String::new(),
+        access_token: Some(intune_token.to_string()),
+        client_info: ClientInfo::default(),
+        id_token: IdToken::default(),
+        prt: None,
+    }
+}
+
+/// Read the delegated (`scp`) and application (`roles`) permissions from an
+/// access token. The token signature is not validated, the claims are only
+/// used to warn about missing permissions.
+fn token_scopes(access_token: &str) -> Option<Vec<String>> {
+    let payload = access_token.split('.').nth(1)?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?)
+            .ok()?;
+    let mut scopes: Vec<String> = match claims.get("scp").and_then(|scp| scp.as_str()) {
+        Some(scp) => scp.split_whitespace().map(|s| s.to_string()).collect(),
+        None => vec![],
+    };
+    if let Some(roles) = claims.get("roles").and_then(|roles| roles.as_array()) {
+        scopes.extend(
+            roles
+                .iter()
+                .filter_map(|role| role.as_str())
+                .map(|role| role.to_string()),
+        );
+    }
+    Some(scopes)
+}
+
+fn warn_missing_scopes(access_token: &str, required: &[&str], endpoint: &str) {
+    let scopes = match token_scopes(access_token) {
+        Some(scopes) => scopes,
+        None => {
+            debug!("Unable to read the permissions of the {} token", endpoint);
+            return;
+        }
+    };
+    if !required
+        .iter()
+        .any(|req| scopes.iter().any(|scope| scope.eq_ignore_ascii_case(req)))
+    {
+        warn!(
+            "The access token for {} lacks the expected permissions (one of {}). \
+            Requests may fail with 403 Forbidden.",
+            endpoint,
+            required.join(", ")
+        );
+    }
+}
+
+/// Construct the Graph client for a domain. The authority, tenant and Graph
+/// url saved during enrollment are used when present; otherwise they are
+/// discovered once via the ODC provider, and the client reuses them for the
+/// rest of the run.
+pub(crate) async fn new_graph(config: &HimmelblauConfig, domain: &str) -> Result<Graph, MsalError> {
+    let authority_host = config.get_authority_host(domain);
+    let tenant_id = config.get_tenant_id(domain);
+    let graph_url = config.get_graph_url(domain);
+    Graph::new(
+        &config.get_odc_provider(domain),
+        domain,
+        Some(&authority_host),
+        tenant_id.as_deref(),
+        graph_url.as_deref(),
+    )
+    .await
+}
+
+/// Return the Graph client for a domain, constructing it on first use. Each
+/// domain (and therefore each tenant) has its own client, so its cloud
+/// settings are respected and connections are pooled per tenant. Clients do
+/// not hold access tokens, these are passed with every request.
+pub(crate) async fn graph_for_domain(
+    config: &HimmelblauConfig,
+    domain: &str,
+) -> Result<Arc<Graph>, MsalError> {
+    static GRAPHS: OnceLock<StdMutex<HashMap<String, Arc<Graph>>>> = OnceLock::new();
+    let key = domain.to_lowercase();
+    let cached = match GRAPHS.get_or_init(Default::default).lock() {
+        Ok(graphs) => graphs.get(&key).cloned(),
+        Err(poisoned) => poisoned.into_inner().get(&key).cloned(),
+    };
+    if let Some(graph) = cached {
+        return Ok(graph);
+    }
+
+    // The lock is not held while constructing the client. If another task
+    // raced us, keep whichever client was cached first.
+    let graph = Arc::new(new_graph(config, domain).await?);
+    let mut graphs = match GRAPHS.get_or_init(Default::default).lock() {
+        Ok(graphs) => graphs,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    Ok(graphs.entry(key).or_insert(graph).clone())
+}
+
+/// The HTTP status of a request rejected by the service. libhimmelblau
+/// reports these with the response status as the error text (e.g. "403
+/// Forbidden"), as either a RequestFailed or a GeneralFailure.
+pub(crate) fn http_status(e: &MsalError) -> Option<u16> {
+    match e {
+        MsalError::RequestFailed(msg) | MsalError::GeneralFailure(msg) => msg
+            .split_whitespace()
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|code| (100..600).contains(code)),
+        _ => None,
+    }
+}
+
+/// Whether a failed request may succeed if retried: it failed to reach the
+/// service, or the service is throttling or temporarily unavailable.
+fn is_transient(e: &MsalError) -> bool {
+    match http_status(e) {
+        Some(status) => status == 429 || status == 503,
+        None => matches!(e, MsalError::RequestFailed(_)),
+    }
+}
+
+const REQUEST_TIMED_OUT: &str = "Request timed out";
+
+/// Whether a request failed because the service could not be reached: the
+/// connection failed or timed out. Failures before a request was sent (e.g.
+/// an invalid url), and any response from the service, are not counted.
+fn is_unreachable(e: &MsalError) -> bool {
+    // libhimmelblau formats the reqwest errors of sending a request with
+    // either Display or Debug, see reqwest::error::Kind
+    const TRANSPORT_ERRORS: &[&str] = &[
+        "error sending request",
+        "error reading a body",
+        "kind: Request",
+        "kind: Body",
+        REQUEST_TIMED_OUT,
+    ];
+    match e {
+        MsalError::RequestFailed(msg) if http_status(e).is_none() => {
+            TRANSPORT_ERRORS.iter().any(|error| msg.contains(error))
+        }
+        _ => false,
+    }
+}
+
+/// Issue a policy request, giving up on each attempt after the configured
+/// timeout. Transient failures are retried with an exponential backoff,
+/// requests rejected by the service are not.
+async fn policy_request<T, F, Fut>(http: &PolicyHttpConfig, mut request: F) -> Result<T, MsalError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, MsalError>>,
+{
+    let mut attempt = 0;
+    loop {
+        let res = match tokio::time::timeout(Duration::from_secs(http.timeout), request()).await {
+            Ok(res) => res,
+            Err(_) => Err(MsalError::RequestFailed(format!(
+                "{} after {} seconds",
+                REQUEST_TIMED_OUT, http.timeout
+            ))),
+        };
+        match res {
+            Err(e) if attempt < http.retries && is_transient(&e) => {
+                let delay =
+                    Duration::from_millis(http.backoff.saturating_mul(1 << attempt.min(16)));
+                attempt += 1;
+                warn!(
+                    "Policy request failed ({}), retrying in {:?} ({}/{})",
+                    e, delay, attempt, http.retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Replace any occurrence of the given secrets (access tokens) in text, so
+/// they never reach the logs or the caller.
+pub(crate) fn redact(text: &str, secrets: &[&str]) -> String {
+    secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(text.to_string(), |text, secret| {
+            text.replace(secret, "[REDACTED]")
+        })
+}
+
+fn msal_error(e: &MsalError, secrets: &[&str]) -> anyhow::Error {
+    anyhow!(redact(&e.to_string(), secrets))
+}
+
+async fn load_local_policies(path: &str) -> Result<IntuneStatus> {
+    let data = fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow!("Failed to read local policy file {}: {}", path, e))?;
+    serde_json::from_str(&data)
+        .map_err(|e| anyhow!("Failed to parse local policy file {}: {}", path, e))
+}
+
+fn extensions(config: &HimmelblauConfig, account_id: &str) -> Result<Vec<Arc<dyn CSE>>> {
+    order_extensions(vec![
+        Arc::new(ScriptsCSE::new(config, account_id)),
+        Arc::new(ComplianceCSE::new(config, account_id)),
+        Arc::new(NetworkManagerCSE::new(config, account_id)),
+        Arc::new(MountCSE::new(config, account_id)),
+        Arc::new(DconfCSE::new(config, account_id)),
+        Arc::new(SudoersCSE::new(config, account_id)),
+        Arc::new(SshdCSE::new(config, account_id)),
+        Arc::new(FirewallCSE::new(config, account_id)),
+        Arc::new(FirefoxCSE::new(config, account_id)),
+        Arc::new(SysctlCSE::new(config, account_id)),
+        Arc::new(JsonSinkCSE::new(config, account_id)),
+    ])
+}
+
+/// Refuse to apply an unreasonable number of policies or settings, e.g. from
+/// a misconfigured tenant.
+fn check_policy_limits(limits: &PolicyLimits, statuses: &IntuneStatus) -> Result<()> {
+    if statuses.policy_statuses.len() > limits.max_policies {
+        return Err(anyhow!(
+            "{} policies are assigned, exceeding max_policies ({})",
+            statuses.policy_statuses.len(),
+            limits.max_policies
+        ));
+    }
+    if let Some(policy) = statuses
+        .policy_statuses
+        .iter()
+        .find(|policy| policy.details.len() > limits.max_settings_per_policy)
+    {
+        return Err(anyhow!(
+            "Policy {} has {} settings, exceeding max_settings_per_policy ({})",
+            policy.policy_id,
+            policy.details.len(),
+            limits.max_settings_per_policy
+        ));
+    }
+    Ok(())
+}
+
+/// Settings which no extension applies, as "<policy id>: <setting id>".
+fn unrecognized_settings(extensions: &[Arc<dyn CSE>], statuses: &IntuneStatus) -> Vec<String> {
+    statuses
+        .policy_statuses
+        .iter()
+        .flat_map(|policy| {
+            policy.details.iter().filter_map(move |detail| {
+                let id = &detail.setting_definition_item_id;
+                let recognized = extensions.iter().any(|ext| {
+                    ext.setting_prefixes()
+                        .iter()
+                        .any(|prefix| id.starts_with(prefix))
+                });
+                (!recognized).then(|| format!("{}: {}", policy.policy_id, id))
+            })
+        })
+        .collect()
+}
+
+/// The result of processing policies with each extension, by name.
+pub type CseResults = Vec<(&'static str, Result<bool>)>;
+
+/// What a policy refresh applied.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct PolicySummary {
+    /// The ids of the policies which were evaluated.
+    pub policies: Vec<String>,
+    /// The extensions which ran, in the order they ran.
+    pub extensions: Vec<ExtensionSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExtensionSummary {
+    pub name: String,
+    /// The number of settings matching the extension's setting prefixes
+    /// which are compliant.
+    pub settings_applied: usize,
+    pub success: bool,
+}
+
+fn summarize(
+    extensions: &[Arc<dyn CSE>],
+    results: &[(&'static str, Result<bool>)],
+    statuses: &IntuneStatus,
+) -> PolicySummary {
+    PolicySummary {
+        policies: statuses
+            .policy_statuses
+            .iter()
+            .map(|policy| policy.policy_id.clone())
+            .collect(),
+        extensions: results
+            .iter()
+            .map(|(name, res)| ExtensionSummary {
+                name: name.to_string(),
+                settings_applied: extensions.iter().find(|ext| ext.name() == *name).map_or(
+                    0,
+                    |ext| {
+                        statuses
+                            .policy_statuses
+                            .iter()
+                            .flat_map(|policy| policy.details.iter())
+                            .filter(|detail| {
+                                detail.new_compliance_state == "Compliant"
+                                    && ext.setting_prefixes().iter().any(|prefix| {
+                                        detail.setting_definition_item_id.starts_with(prefix)
+                                    })
+                            })
+                            .count()
+                    },
+                ),
+                success: res.is_ok(),
+            })
+            .collect(),
+    }
+}
+
+/// Process the policies with each extension, recording which completed. If
+/// an earlier apply of the same policies was interrupted, the extensions which
+/// completed then are skipped, and their compliance state is carried over.
+async fn run_extensions(
+    extensions: &[Arc<dyn CSE>],
+    account_id: &str,
+    statuses: &mut IntuneStatus,
+    cache: &dyn SnapshotCache,
+) -> CseResults {
+    let mut completed = match cache.load_progress(account_id).await {
+        Ok(Some(progress))
+            if diff_snapshots(
+                &progress.statuses.policy_statuses,
+                &statuses.policy_statuses,
+            )
+            .is_empty() =>
+        {
+            info!(
+                "Resuming an interrupted policy apply, skipping completed extensions: {}",
+                progress.completed.join(", ")
+            );
+            restore_compliance(&progress.statuses, statuses);
+            progress.completed
+        }
+        Ok(_) => vec![],
+        Err(e) => {
+            error!("Failed to load policy apply progress: {:?}", e);
+            vec![]
+        }
+    };
+
+    let mut results = vec![];
+    for ext in extensions {
+        if completed.iter().any(|name| name == ext.name()) {
+            results.push((ext.name(), Ok(true)));
+            continue;
+        }
+        let res = ext.process_group_policy(statuses).await;
+        if res.is_ok() {
+            completed.push(ext.name().to_string());
+            if let Err(e) = cache.store_progress(account_id, statuses, &completed).await {
+                error!("Failed to save policy apply progress: {:?}", e);
+            }
+        }
+        results.push((ext.name(), res));
+    }
+    if let Err(e) = cache.clear_progress(account_id).await {
+        error!("Failed to clear policy apply progress: {:?}", e);
+    }
+    results
+}
+
+async fn enforce_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    statuses: &mut IntuneStatus,
+    cache: &dyn SnapshotCache,
+) -> (PolicySummary, Vec<anyhow::Error>) {
+    sort_policies(&mut statuses.policy_statuses);
+
+    // Report what changed since the last applied snapshot
+    match diff_last_applied(cache, account_id, statuses).await {
+        Ok(Some(diff)) if !diff.is_empty() => {
+            info!("Policy changes since the last refresh:\n{}", diff);
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to load policy snapshot: {:?}", e),
+    }
+
+    let gp_extensions = match extensions(config, account_id) {
+        Ok(gp_extensions) => gp_extensions,
+        Err(e) => return (summarize(&[], &[], statuses), vec![e]),
+    };
+
+    let results = run_extensions(&gp_extensions, account_id, statuses, cache).await;
+    let summary = summarize(&gp_extensions, &results, statuses);
+    let mut errors: Vec<anyhow::Error> = results
+        .into_iter()
+        .filter_map(|(_, res)| res.err())
+        .collect();
+
+    let unrecognized = unrecognized_settings(&gp_extensions, statuses);
+    for setting in &unrecognized {
+        warn!("Skipping unrecognized setting {}", setting);
+    }
+    if config.get_policy_strict_settings() && !unrecognized.is_empty() {
+        errors.push(anyhow!(
+            "Unrecognized settings: {}",
+            unrecognized.join(", ")
+        ));
+    }
+
+    if let Err(e) = cache.store(account_id, statuses).await {
+        error!("Failed to save policy snapshot: {:?}", e);
+    }
+    if let Some(dir) = config.get_policy_audit_dir() {
+        match write_audit(Path::new(&dir), account_id, statuses).await {
+            Ok(path) => debug!("Wrote policy audit record {}", path.display()),
+            Err(e) => error!("Failed to write policy audit record: {:?}", e),
+        }
+    }
+    (summary, errors)
+}
+
+/// Apply `statuses` only if they differ from the last applied snapshot.
+///
+/// Each CSE reconciles the complete set of assigned policies, applying added
+/// and changed settings and removing those no longer assigned, so when
+/// anything changed all CSEs are processed. When nothing changed, no CSE is
+/// processed and the compliance state of each setting is carried over from
+/// the snapshot. Returns the changes and the result of each CSE which ran.
+pub async fn apply_changed_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    statuses: &mut IntuneStatus,
+    cache: &dyn SnapshotCache,
+) -> Result<(PolicyDiff, CseResults)> {
+    apply_changed(
+        &extensions(config, account_id)?,
+        account_id,
+        statuses,
+        cache,
+    )
+    .await
+}
+
+async fn apply_changed(
+    extensions: &[Arc<dyn CSE>],
+    account_id: &str,
+    statuses: &mut IntuneStatus,
+    cache: &dyn SnapshotCache,
+) -> Result<(PolicyDiff, CseResults)> {
+    sort_policies(&mut statuses.policy_statuses);
+    let previous = cache.load(account_id).await?;
+    let diff = match &previous {
+        Some(previous) => diff_snapshots(&previous.policy_statuses, &statuses.policy_statuses),
+        None => diff_snapshots(&[], &statuses.policy_statuses),
+    };
+
+    if let Some(previous) = previous.filter(|_| diff.is_empty()) {
+        debug!("Policies are unchanged since the last refresh");
+        restore_compliance(&previous, statuses);
+        return Ok((diff, vec![]));
+    }
+
+    let results = run_extensions(extensions, account_id, statuses, cache).await;
+    cache.store(account_id, statuses).await?;
+    Ok((diff, results))
+}
+
+/* Policy application is serialized per account, so that a login and a
+ * periodic refresh firing together do not race over the same cron jobs,
+ * caches and snapshots. Different accounts still apply concurrently.
+ */
+fn apply_lock(account_id: &str) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<StdMutex<HashMap<String, Arc<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = match LOCKS.get_or_init(Default::default).lock() {
+        Ok(locks) => locks,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    locks.entry(account_id.to_string()).or_default().clone()
+}
+
+/* With policy_debug enabled, policy troubleshooting output is logged at info
+ * level, so that it reaches the journal without enabling debug logging for
+ * the entire daemon.
+ */
+macro_rules! policy_debug {
+    ($verbose:expr, $($arg:tt)+) => {
+        if $verbose {
+            info!($($arg)+)
+        } else {
+            debug!($($arg)+)
+        }
+    };
+}
+
+/// Re-read policy_debug from the config file on each refresh, so it can be
+/// toggled without restarting the daemon.
+pub(crate) fn policy_debug_enabled(config: &HimmelblauConfig) -> bool {
+    match HimmelblauConfig::new(Some(&config.get_config_file())) {
+        Ok(current) => current.get_policy_debug(),
+        Err(_) => config.get_policy_debug(),
+    }
+}
+
+/// Fetch, enforce and report the Intune policies for a user and device.
+///
+/// Each endpoint requires a token for its own audience:
+/// - `graph_token` for Microsoft Graph, used only to discover the Intune
+///   service endpoints (see INTUNE_ENDPOINTS_SCOPES for the permissions).
+/// - `intune_token` for the Intune resource
+///   (0000000a-0000-0000-c000-000000000000), used for the device details,
+///   policy and status requests to the Intune check-in service.
 pub async fn apply_intune_policy(
     config: &HimmelblauConfig,
     account_id: &str,
     graph_token: &str,
     intune_token: &str,
 ) -> Result<bool> {
-    debug!(?account_id, "Attempting to enforce policies");
+    apply_intune_policy_summary(config, account_id, graph_token, intune_token)
+        .await
+        .map(|_| true)
+}
+
+/// Like apply_intune_policy(), but returns a summary of the policies which
+/// were evaluated and the extensions which applied them.
+#[instrument(skip(config, graph_token, intune_token))]
+pub async fn apply_intune_policy_summary(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    graph_token: &str,
+    intune_token: &str,
+) -> Result<PolicySummary> {
+    let lock = apply_lock(account_id);
+    let _guard = lock.lock().await;
+    let mut run = PolicyRunStatus::default();
+    let res = with_deadline(
+        config
+            .get_policy_refresh_deadline()
+            .map(Duration::from_secs),
+        apply_policies(config, account_id, graph_token, intune_token, &mut run),
+    )
+    .await;
+
+    if let Some(path) = config.get_policy_status_textfile() {
+        run.timestamp = chrono::Utc::now().timestamp();
+        run.success = res.is_ok();
+        if let Err(e) = record_run(Path::new(&path), account_id, run).await {
+            warn!("Failed to write policy status file {}: {:?}", path, e);
+        }
+    }
+    res
+}
+
+async fn apply_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    graph_token: &str,
+    intune_token: &str,
+    run: &mut PolicyRunStatus,
+) -> Result<PolicySummary> {
+    let verbose = policy_debug_enabled(config);
+    policy_debug!(verbose, ?account_id, "Attempting to enforce policies");
+    let secrets = [graph_token, intune_token];
+
+    // A local policy file replaces the Intune assigned policies entirely
+    if let Some(local_policy_file) = config.get_local_policy_file() {
+        if Path::new(&local_policy_file).exists() {
+            policy_debug!(
+                verbose,
+                ?local_policy_file,
+                "Enforcing policies from local policy file"
+            );
+            let mut statuses = load_local_policies(&local_policy_file).await?;
+            check_policy_limits(&config.get_policy_limits(), &statuses)?;
+            let (summary, errors) = enforce_policies(
+                config,
+                account_id,
+                &mut statuses,
+                &FileSnapshotCache::new(config),
+            )
+            .await;
+            run.policies_applied = statuses.policy_statuses.len();
+            run.cse_failures = errors.len();
+            policy_debug!(verbose, "Enforced local policy:\n{:#?}", statuses);
+            return if !errors.is_empty() {
+                Err(anyhow!("Policy enforcement failed: {:?}", errors))
+            } else {
+                Ok(summary)
+            };
+        }
+    }
+
+    let (domain, intune_device_id) = match enrolled_device(config, account_id, verbose)? {
+        Some(device) => device,
+        None => return Ok(PolicySummary::default()),
+    };
+    let target = match discover_target(config, account_id, domain, intune_device_id, verbose).await
+    {
+        Ok(target) => target,
+        Err(e) => return fetch_failed(config, account_id, run, e, &secrets).await,
+    };
+    if !with_breaker(&target.graph_url, |breaker| breaker.allow(Instant::now())) {
+        warn!(
+            "Policy service for {} is unreachable, enforcing the last applied policies",
+            target.graph_url
+        );
+        return apply_cached_policies(config, account_id, run).await;
+    }
+
+    let http = config.get_policy_http_config();
+    let intune = match intune_client(&target, &http, graph_token, verbose).await {
+        Ok(intune) => intune,
+        Err(e) => return fetch_failed(config, account_id, run, e, &secrets).await,
+    };
+    let token = intune_user_token(intune_token);
+
+    // Update device details
+    let attrs = EnrollAttrs::new(target.domain.clone(), None, None, None, None)
+        .map_err(|e| msal_error(&e, &secrets))?;
+    let details = policy_request(&http, || {
+        intune.details(&token, &attrs, &target.intune_device_id)
+    })
+    .await;
+    if let Err(e) = track(&target.graph_url, details) {
+        return fetch_failed(config, account_id, run, e, &secrets).await;
+    }
+    policy_debug!(verbose, "Updated Intune device details");
+
+    let mut statuses = match fetch_policies(&target, &http, &intune, &token, verbose).await {
+        Ok(statuses) => statuses,
+        Err(e) => return fetch_failed(config, account_id, run, e, &secrets).await,
+    };
+    check_policy_limits(&config.get_policy_limits(), &statuses)?;
 
+    let cache = FileSnapshotCache::new(config);
+    if let Err(e) = cache
+        .store_fetched(account_id, chrono::Utc::now().timestamp())
+        .await
+    {
+        error!("Failed to record the policy fetch time: {:?}", e);
+    }
+    let (summary, errors) = enforce_policies(config, account_id, &mut statuses, &cache).await;
+    run.policies_applied = statuses.policy_statuses.len();
+    run.cse_failures = errors.len();
+    policy_debug!(verbose, "Enforced Intune policy");
+
+    // Report policy status
+    policy_debug!(verbose, "Reporting Intune policy status:\n{:#?}", statuses);
+    intune
+        .status(&token, statuses)
+        .await
+        .map_err(|e| msal_error(&e, &secrets))?;
+
+    if !errors.is_empty() {
+        Err(anyhow!("Policy enforcement failed: {:?}", errors))
+    } else {
+        Ok(summary)
+    }
+}
+
+/// The enrolled device whose Intune policies are fetched.
+struct PolicyTarget {
+    domain: String,
+    intune_device_id: String,
+    graph: Arc<Graph>,
+    graph_url: String,
+}
+
+/// Find the enrolled domain and Intune device id for an account. Returns None
+/// if the device isn't enrolled in Intune.
+pub(crate) fn enrolled_device(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    verbose: bool,
+) -> Result<Option<(String, String)>> {
     let domain = split_username(account_id)
         .map(|(_, domain)| domain)
         .ok_or(anyhow!(
             "Failed to parse domain name from account id '{}'",
             account_id
         ))?;
+    // Use the enrollment data of the device's tenant when the account domain
+    // isn't configured itself (e.g. it is a domain alias)
+    let enrolled_domain = config.get_enrolled_domain(domain);
+    let domain = enrolled_domain.as_deref().unwrap_or(domain);
 
     let intune_device_id = match config.get_intune_device_id(domain) {
         Some(id) => id,
         // This device isn't enrolled in Intune, there is nothing to enforce
         None => {
-            debug!("Device not enrolled in Intune, skipping");
-            return Ok(true);
+            policy_debug!(verbose, "Device not enrolled in Intune, skipping");
+            return Ok(None);
         }
     };
-    debug!(
+    // Don't contact Intune with a device id it can't know about
+    if Uuid::parse_str(&intune_device_id).map_or(true, |id| id.is_nil()) {
+        return Err(DeviceNotJoined(intune_device_id).into());
+    }
+    Ok(Some((domain.to_string(), intune_device_id)))
+}
+
+/// Find the Intune device and Graph client for an account. Returns None if
+/// the device isn't enrolled in Intune.
+async fn policy_target(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    secrets: &[&str],
+    verbose: bool,
+) -> Result<Option<PolicyTarget>> {
+    let (domain, intune_device_id) = match enrolled_device(config, account_id, verbose)? {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    discover_target(config, account_id, domain, intune_device_id, verbose)
+        .await
+        .map(Some)
+        .map_err(|e| msal_error(&e, secrets))
+}
+
+/// Discover the Graph client of the enrolled device of an account.
+async fn discover_target(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    domain: String,
+    intune_device_id: String,
+    verbose: bool,
+) -> Result<PolicyTarget, MsalError> {
+    policy_debug!(
+        verbose,
         ?account_id,
         ?intune_device_id,
         "Applying policies for user and device"
     );
 
-    let graph = Graph::new(&config.get_odc_provider(domain), domain, None, None, None)
+    let graph = graph_for_domain(config, &domain).await?;
+    let graph_url = graph
+        .graph_url()
         .await
-        .map_err(|e| anyhow!(e))?;
+        .unwrap_or_else(|_| domain.to_string());
+    Ok(PolicyTarget {
+        domain,
+        intune_device_id,
+        graph,
+        graph_url,
+    })
+}
 
-    let endpoints = graph
-        .intune_service_endpoints(graph_token)
+/// Discover the Intune service endpoints and construct the Intune client.
+async fn intune_client(
+    target: &PolicyTarget,
+    http: &PolicyHttpConfig,
+    graph_token: &str,
+    verbose: bool,
+) -> Result<IntuneForLinux, MsalError> {
+    warn_missing_scopes(
+        graph_token,
+        INTUNE_ENDPOINTS_SCOPES,
+        "Intune service endpoints",
+    );
+    let endpoints =
+        policy_request(http, || target.graph.intune_service_endpoints(graph_token)).await;
+    let endpoints = track(&target.graph_url, endpoints)?;
+    policy_debug!(
+        verbose,
+        "Discovered Intune service endpoints, check-in service {:?}",
+        endpoints.get("LinuxDeviceCheckinService").ok()
+    );
+    IntuneForLinux::new(endpoints)
+}
+
+/// Get the list of policies assigned to the device.
+async fn fetch_policies(
+    target: &PolicyTarget,
+    http: &PolicyHttpConfig,
+    intune: &IntuneForLinux,
+    token: &UserToken,
+    verbose: bool,
+) -> Result<IntuneStatus, MsalError> {
+    let policies = policy_request(http, || intune.policies(token, &target.intune_device_id)).await;
+    let policies = track(&target.graph_url, policies)?;
+    with_breaker(&target.graph_url, |breaker| breaker.record_success());
+    policy_debug!(
+        verbose,
+        "Received policy enforcement actions:\n{:#?}",
+        policies
+    );
+    let mut statuses: IntuneStatus = policies.into();
+    statuses.set_device_id(target.intune_device_id.clone());
+    Ok(statuses)
+}
+
+/// Fetch the policies assigned to the device and store them in `cache`,
+/// without applying them. The device applies the prefetched policies on its
+/// first refresh, or if the policy service is unreachable then, in their
+/// place. Returns the number of policies stored.
+///
+/// This allows staging the policies of a device ahead of time, e.g. while
+/// provisioning a golden image. The intune_device_id configured for the
+/// account's domain must be the id of the device which applies the policies.
+pub async fn prefetch_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    graph_token: &str,
+    intune_token: &str,
+    cache: &dyn SnapshotCache,
+) -> Result<usize> {
+    let verbose = policy_debug_enabled(config);
+    let secrets = [graph_token, intune_token];
+    let target = policy_target(config, account_id, &secrets, verbose)
+        .await?
+        .ok_or(anyhow!("Device is not enrolled in Intune"))?;
+    let http = config.get_policy_http_config();
+    let intune = intune_client(&target, &http, graph_token, verbose)
         .await
-        .map_err(|e| anyhow!(e))?;
-    debug!("Discovered Intune service endpoints");
+        .map_err(|e| msal_error(&e, &secrets))?;
+    let token = intune_user_token(intune_token);
+    let mut statuses = fetch_policies(&target, &http, &intune, &token, verbose)
+        .await
+        .map_err(|e| msal_error(&e, &secrets))?;
+    check_policy_limits(&config.get_policy_limits(), &statuses)?;
+    sort_policies(&mut statuses.policy_statuses);
+    // Stored as an apply which no CSE has completed yet
+    cache.store_progress(account_id, &statuses, &[]).await?;
+    cache
+        .store_fetched(account_id, chrono::Utc::now().timestamp())
+        .await?;
+    Ok(statuses.policy_statuses.len())
+}
 
-    let intune = IntuneForLinux::new(endpoints).map_err(|e| anyhow!(e))?;
+/// What applying the assigned policies would do, without applying them.
+#[derive(Debug)]
+pub struct PolicyPreview {
+    /// The changes since the last applied snapshot. None if no policies were
+    /// applied previously.
+    pub diff: Option<PolicyDiff>,
+    /// What each extension would apply, by extension name.
+    pub extensions: Vec<(&'static str, Vec<String>)>,
+    /// Settings which no extension applies.
+    pub unrecognized: Vec<String>,
+}
 
-    let token = UserToken {
-        token_type: String::new(),
-        scope: None,
-        expires_in: 0,
-        ext_expires_in: 0,
-        refresh_token: String::new(),
-        access_token: Some(intune_token.to_string()),
-        client_info: ClientInfo::default(),
-        id_token: IdToken::default(),
-        prt: None,
+fn preview(
+    extensions: &[Arc<dyn CSE>],
+    statuses: &IntuneStatus,
+    previous: Option<&IntuneStatus>,
+) -> PolicyPreview {
+    PolicyPreview {
+        diff: previous
+            .map(|previous| diff_snapshots(&previous.policy_statuses, &statuses.policy_statuses)),
+        extensions: extensions
+            .iter()
+            .map(|ext| (ext.name(), ext.preview_group_policy(statuses)))
+            .collect(),
+        unrecognized: unrecognized_settings(extensions, statuses),
+    }
+}
+
+/// Fetch the policies assigned to the device (or read the local policy file),
+/// ordered as they would be applied, without invoking any extension or
+/// reporting status to Intune. This allows tooling to inspect the policies,
+/// and their settings, which apply to an account.
+pub async fn resolve_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    graph_token: &str,
+    intune_token: &str,
+) -> Result<IntuneStatus> {
+    let verbose = policy_debug_enabled(config);
+    let secrets = [graph_token, intune_token];
+    let local_policy_file = config
+        .get_local_policy_file()
+        .filter(|path| Path::new(path).exists());
+    let mut statuses = match local_policy_file {
+        Some(local_policy_file) => load_local_policies(&local_policy_file).await?,
+        None => {
+            let target = policy_target(config, account_id, &secrets, verbose)
+                .await?
+                .ok_or(anyhow!("Device is not enrolled in Intune"))?;
+            let http = config.get_policy_http_config();
+            let intune = intune_client(&target, &http, graph_token, verbose)
+                .await
+                .map_err(|e| msal_error(&e, &secrets))?;
+            let token = intune_user_token(intune_token);
+            fetch_policies(&target, &http, &intune, &token, verbose)
+                .await
+                .map_err(|e| msal_error(&e, &secrets))?
+        }
     };
+    check_policy_limits(&config.get_policy_limits(), &statuses)?;
+    sort_policies(&mut statuses.policy_statuses);
+    Ok(statuses)
+}
 
-    // Update device details
-    let attrs =
-        EnrollAttrs::new(domain.to_string(), None, None, None, None).map_err(|e| anyhow!(e))?;
-    intune
-        .details(&token, &attrs, &intune_device_id)
-        .await
-        .map_err(|e| anyhow!(e))?;
-    debug!("Updated Intune device details");
+/// Fetch the policies assigned to the device (or read the local policy file)
+/// and describe what applying them would do, without changing the system or
+/// reporting status to Intune.
+pub async fn preview_intune_policy(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    graph_token: &str,
+    intune_token: &str,
+) -> Result<PolicyPreview> {
+    let statuses = resolve_policies(config, account_id, graph_token, intune_token).await?;
+    let previous = FileSnapshotCache::new(config).load(account_id).await?;
+    Ok(preview(
+        &extensions(config, account_id)?,
+        &statuses,
+        previous.as_ref(),
+    ))
+}
 
-    // Get the list of policies to apply
-    let policies = intune
-        .policies(&token, &intune_device_id)
-        .await
-        .map_err(|e| anyhow!(e))?;
-    debug!("Received policy enforcement actions:\n{:#?}", policies);
-    let mut statuses: IntuneStatus = policies.into();
-    statuses.set_device_id(intune_device_id);
+/// Record a failure to reach the policy service with its circuit breaker.
+/// Any response from the service, even an error, shows it is reachable. A
+/// request which failed before it was sent says nothing about the service.
+fn track<T>(graph_url: &str, res: Result<T, MsalError>) -> Result<T, MsalError> {
+    with_breaker(graph_url, |breaker| match &res {
+        Err(e) if is_unreachable(e) => breaker.record_failure(Instant::now()),
+        Err(e @ MsalError::RequestFailed(_)) if http_status(e).is_none() => {}
+        Err(_) => breaker.record_success(),
+        Ok(_) => {}
+    });
+    res
+}
 
-    let gp_extensions: Vec<Arc<dyn CSE>> = vec![
-        Arc::new(ScriptsCSE::new(config, account_id)),
-        Arc::new(ComplianceCSE::new(config, account_id)),
-    ];
+/// The policies to enforce in place of those assigned, when a request to the
+/// policy service failed with `e`: the last applied, or otherwise the
+/// prefetched, policies, if the service was unreachable and they have not
+/// expired.
+async fn fallback_policies(
+    cache: &dyn SnapshotCache,
+    account_id: &str,
+    max_age: Option<u64>,
+    now: i64,
+    e: &MsalError,
+) -> Option<IntuneStatus> {
+    if !is_unreachable(e) {
+        return None;
+    }
+    match cached_policies(cache, account_id, max_age, now).await {
+        Ok(statuses) => Some(statuses),
+        Err(e) => {
+            debug!("Not enforcing cached policies: {:?}", e);
+            None
+        }
+    }
+}
 
-    let mut errors = vec![];
-    for ext in gp_extensions {
-        match ext.process_group_policy(&mut statuses).await {
-            Ok(_) => {}
-            Err(e) => {
-                errors.push(e);
-            }
+/// A request to the policy service (Graph discovery, the device details, the
+/// service endpoints or the policies) failed. If the service was unreachable,
+/// enforce the cached policies instead.
+async fn fetch_failed(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    run: &mut PolicyRunStatus,
+    e: MsalError,
+    secrets: &[&str],
+) -> Result<PolicySummary> {
+    let cache = FileSnapshotCache::new(config);
+    match fallback_policies(
+        &cache,
+        account_id,
+        config.get_policy_cache_max_age(),
+        chrono::Utc::now().timestamp(),
+        &e,
+    )
+    .await
+    {
+        Some(statuses) => {
+            warn!(
+                "Policy service is unreachable ({}), enforcing the cached policies",
+                redact(&e.to_string(), secrets)
+            );
+            enforce_cached_policies(config, account_id, run, statuses, &cache).await
         }
+        None => Err(msal_error(&e, secrets)),
     }
-    debug!("Enforced Intune policy");
+}
 
-    // Report policy status
-    debug!("Reporting Intune policy status:\n{:#?}", statuses);
-    intune
-        .status(&token, statuses)
-        .await
-        .map_err(|e| anyhow!(e))?;
+/// Load the last applied, or otherwise the prefetched, policies. Policies
+/// fetched from the policy service more than `max_age` seconds before `now`,
+/// or at an unknown time, have expired.
+async fn cached_policies(
+    cache: &dyn SnapshotCache,
+    account_id: &str,
+    max_age: Option<u64>,
+    now: i64,
+) -> Result<IntuneStatus> {
+    let statuses = match cache.load(account_id).await? {
+        Some(statuses) => statuses,
+        None => {
+            cache
+                .load_progress(account_id)
+                .await?
+                .ok_or(anyhow!(
+                    "Policy service is unreachable, and no policies were applied previously"
+                ))?
+                .statuses
+        }
+    };
+    if let Some(max_age) = max_age {
+        let fetched = cache.load_fetched(account_id).await?;
+        if fetched.map_or(true, |fetched| now.saturating_sub(fetched) > max_age as i64) {
+            return Err(anyhow!(
+                "Policy service is unreachable, and the cached policies are older than {} seconds",
+                max_age
+            ));
+        }
+    }
+    Ok(statuses)
+}
+
+/// Enforce the last applied, or otherwise the prefetched, policies while the
+/// policy service is unreachable. No status is reported to Intune.
+async fn apply_cached_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    run: &mut PolicyRunStatus,
+) -> Result<PolicySummary> {
+    let cache = FileSnapshotCache::new(config);
+    let statuses = cached_policies(
+        &cache,
+        account_id,
+        config.get_policy_cache_max_age(),
+        chrono::Utc::now().timestamp(),
+    )
+    .await?;
+    enforce_cached_policies(config, account_id, run, statuses, &cache).await
+}
 
+/// Enforce cached policies. No status is reported to Intune.
+async fn enforce_cached_policies(
+    config: &HimmelblauConfig,
+    account_id: &str,
+    run: &mut PolicyRunStatus,
+    mut statuses: IntuneStatus,
+    cache: &FileSnapshotCache,
+) -> Result<PolicySummary> {
+    let (summary, errors) = enforce_policies(config, account_id, &mut statuses, cache).await;
+    run.policies_applied = statuses.policy_statuses.len();
+    run.cse_failures = errors.len();
     if !errors.is_empty() {
         Err(anyhow!("Policy enforcement failed: {:?}", errors))
     } else {
-        Ok(true)
+        Ok(summary)
+    }
+}
+
+// Never refresh more often than every five minutes
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Compute the delay until the next policy refresh.
+///
+/// Similar to the Windows group policy refresh, a random offset of up to a
+/// third of the base `interval` is added so that a fleet of devices does not
+/// contact Intune in lockstep. The interval is clamped to a minimum of five
+/// minutes. Passing a `seed` makes the returned delay deterministic.
+pub fn next_refresh_delay(interval: Duration, seed: Option<u64>) -> Duration {
+    let interval = interval.max(MIN_REFRESH_INTERVAL);
+    let max_jitter = interval.as_millis() as u64 / 3;
+    let jitter = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed).random_range(0..=max_jitter),
+        None => rand::rng().random_range(0..=max_jitter),
+    };
+    interval + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::{policy, MemorySnapshotCache};
+    use async_trait::async_trait;
+
+    /// Marks every setting compliant, and counts how often it ran.
+    struct CountingCSE {
+        name: &'static str,
+        runs: StdMutex<usize>,
+    }
+
+    impl CountingCSE {
+        fn named(name: &'static str) -> Self {
+            CountingCSE {
+                name,
+                runs: StdMutex::new(0),
+            }
+        }
+
+        fn runs(&self) -> usize {
+            self.runs.lock().map(|runs| *runs).unwrap_or_default()
+        }
+    }
+
+    #[async_trait]
+    impl CSE for CountingCSE {
+        fn new(_config: &HimmelblauConfig, _username: &str) -> Self {
+            CountingCSE::named("counting")
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+            for policy in policies.policy_statuses.iter_mut() {
+                for detail in policy.details.iter_mut() {
+                    detail.actual_value = detail.expected_value.clone();
+                    detail.new_compliance_state = "Compliant".to_string();
+                }
+            }
+            let mut runs = self.runs.lock().map_err(|e| anyhow!("{}", e))?;
+            *runs += 1;
+            Ok(true)
+        }
+    }
+
+    /// Never completes, as if the device lost power while it was running.
+    struct HangingCSE;
+
+    #[async_trait]
+    impl CSE for HangingCSE {
+        fn new(_config: &HimmelblauConfig, _username: &str) -> Self {
+            HangingCSE
+        }
+
+        fn name(&self) -> &'static str {
+            "hanging"
+        }
+
+        async fn process_group_policy(&self, _policies: &mut IntuneStatus) -> Result<bool> {
+            std::future::pending::<()>().await;
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_apply() -> Result<()> {
+        let cache = MemorySnapshotCache::default();
+        let account_id = "tux@example.com";
+        let statuses = |value: &str| IntuneStatus {
+            device_id: Some("device".to_string()),
+            policy_statuses: vec![policy("a", &[("linux_mount_where", value)])],
+        };
+        let first = Arc::new(CountingCSE::named("first"));
+        let crashing: Vec<Arc<dyn CSE>> = vec![first.clone(), Arc::new(HangingCSE)];
+        let crash = |statuses: IntuneStatus| {
+            let crashing = crashing.clone();
+            let cache = &cache;
+            async move {
+                let mut statuses = statuses;
+                tokio::time::timeout(
+                    Duration::from_millis(100),
+                    run_extensions(&crashing, account_id, &mut statuses, cache),
+                )
+                .await
+                .is_err()
+            }
+        };
+
+        // Interrupted after the first extension, the resumed apply skips it
+        assert!(crash(statuses("/mnt/a")).await);
+        assert_eq!(first.runs(), 1);
+        let second = Arc::new(CountingCSE::named("hanging"));
+        let resumed: Vec<Arc<dyn CSE>> = vec![first.clone(), second.clone()];
+        let mut current = statuses("/mnt/a");
+        let results = run_extensions(&resumed, account_id, &mut current, &cache).await;
+        assert!(matches!(
+            results.as_slice(),
+            [("first", Ok(true)), ("hanging", Ok(true))]
+        ));
+        assert_eq!((first.runs(), second.runs()), (1, 1));
+        assert!(cache.load_progress(account_id).await?.is_none());
+
+        // When the policies changed in between, everything is applied again
+        assert!(crash(statuses("/mnt/a")).await);
+        let mut current = statuses("/mnt/b");
+        run_extensions(&resumed, account_id, &mut current, &cache).await;
+        assert_eq!((first.runs(), second.runs()), (3, 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(true)
+        };
+        assert!(with_deadline(Some(Duration::from_millis(50)), slow)
+            .await
+            .is_err_and(|e| e.downcast_ref::<DeadlineExceeded>().is_some()));
+        assert!(
+            with_deadline(Some(Duration::from_secs(60)), async { Ok(true) })
+                .await
+                .is_ok()
+        );
+        assert!(with_deadline(None, async { Ok(true) }).await.is_ok());
+    }
+
+    #[test]
+    fn test_check_policy_limits() {
+        let statuses = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy("a", &[("linux_mount_where", "/mnt/a")]),
+                policy(
+                    "b",
+                    &[("linux_mount_where", "/mnt/b"), ("linux_mount_type", "nfs")],
+                ),
+            ],
+        };
+        let limits = |max_policies, max_settings_per_policy| PolicyLimits {
+            max_policies,
+            max_settings_per_policy,
+        };
+        assert!(check_policy_limits(&limits(2, 2), &statuses).is_ok());
+        assert!(check_policy_limits(&limits(1, 2), &statuses).is_err());
+        assert!(check_policy_limits(&limits(2, 1), &statuses)
+            .is_err_and(|e| e.to_string().starts_with("Policy b has 2 settings")));
+    }
+
+    #[test]
+    fn test_unrecognized_settings() -> Result<()> {
+        let config = HimmelblauConfig::new(None).map_err(|e| anyhow!(e))?;
+        let statuses = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy("a", &[("linux_mount_where", "/mnt/a")]),
+                policy(
+                    "b",
+                    &[
+                        ("linux_customconfig_script", "ZWNobw=="),
+                        ("linux_future_setting", "1"),
+                    ],
+                ),
+            ],
+        };
+        assert_eq!(
+            unrecognized_settings(&extensions(&config, "tux@example.com")?, &statuses),
+            vec!["b: linux_future_setting".to_string()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summarize() -> Result<()> {
+        let config = HimmelblauConfig::new(None).map_err(|e| anyhow!(e))?;
+        let mut statuses = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy(
+                    "a",
+                    &[
+                        ("linux_mount_where", "/mnt/a"),
+                        ("linux_mount_what", "nfs:/a"),
+                    ],
+                ),
+                policy("b", &[("linux_dconf_idle", "{}")]),
+            ],
+        };
+        for detail in statuses
+            .policy_statuses
+            .iter_mut()
+            .flat_map(|policy| policy.details.iter_mut())
+            .filter(|detail| {
+                detail
+                    .setting_definition_item_id
+                    .starts_with("linux_mount_")
+            })
+        {
+            detail.new_compliance_state = "Compliant".to_string();
+        }
+        let results = vec![("mounts", Ok(true)), ("dconf", Err(anyhow!("failed")))];
+
+        let summary = summarize(
+            &extensions(&config, "tux@example.com")?,
+            &results,
+            &statuses,
+        );
+        assert_eq!(summary.policies, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(
+            summary.extensions,
+            vec![
+                ExtensionSummary {
+                    name: "mounts".to_string(),
+                    settings_applied: 2,
+                    success: true,
+                },
+                ExtensionSummary {
+                    name: "dconf".to_string(),
+                    settings_applied: 0,
+                    success: false,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview() -> Result<()> {
+        let config = HimmelblauConfig::new(None).map_err(|e| anyhow!(e))?;
+        let statuses = |policies: Vec<himmelblau::intune::PolicyStatus>| IntuneStatus {
+            device_id: None,
+            policy_statuses: policies,
+        };
+        let previous = statuses(vec![policy("a", &[("linux_mount_where", "/mnt/a")])]);
+        let current = statuses(vec![
+            policy("a", &[("linux_mount_where", "/mnt/b")]),
+            policy(
+                "b",
+                &[
+                    ("linux_customconfig_script", "ZWNobw=="),
+                    ("linux_future_setting", "1"),
+                ],
+            ),
+        ]);
+        let extensions = extensions(&config, "tux@example.com")?;
+
+        let res = preview(&extensions, &current, Some(&previous));
+        assert!(res.diff.is_some_and(|diff| diff.changed.len() == 1
+            && diff.added.len() == 2
+            && diff.removed.is_empty()));
+        let settings = |name: &str| {
+            res.extensions
+                .iter()
+                .find(|(ext, _)| *ext == name)
+                .map(|(_, settings)| settings.clone())
+                .unwrap_or_default()
+        };
+        assert_eq!(settings("mounts"), vec!["a: linux_mount_where".to_string()]);
+        assert_eq!(
+            settings("scripts"),
+            vec!["b: linux_customconfig_script".to_string()]
+        );
+        assert!(settings("dconf").is_empty());
+        assert_eq!(
+            res.unrecognized,
+            vec!["b: linux_future_setting".to_string()]
+        );
+
+        assert!(preview(&extensions, &current, None).diff.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_changed_policies() -> Result<()> {
+        let cse = Arc::new(CountingCSE::named("counting"));
+        let extensions: Vec<Arc<dyn CSE>> = vec![cse.clone()];
+        let cache = MemorySnapshotCache::default();
+        let account_id = "tux@example.com";
+        let statuses = |policies: Vec<himmelblau::intune::PolicyStatus>| IntuneStatus {
+            device_id: Some("device".to_string()),
+            policy_statuses: policies,
+        };
+        let runs = || cse.runs();
+
+        // Add only
+        let mut current = statuses(vec![
+            policy("a", &[("linux_mount_where", "/mnt/a")]),
+            policy("b", &[("linux_mount_where", "/mnt/b")]),
+        ]);
+        let (diff, results) = apply_changed(&extensions, account_id, &mut current, &cache).await?;
+        assert_eq!(diff.added.len(), 2);
+        assert!(diff.changed.is_empty() && diff.removed.is_empty());
+        assert!(matches!(results.as_slice(), [("counting", Ok(true))]));
+        assert_eq!(runs(), 1);
+
+        // Unchanged, no CSE runs and the compliance state is kept
+        let mut current = statuses(vec![
+            policy("b", &[("linux_mount_where", "/mnt/b")]),
+            policy("a", &[("linux_mount_where", "/mnt/a")]),
+        ]);
+        let (diff, results) = apply_changed(&extensions, account_id, &mut current, &cache).await?;
+        assert!(diff.is_empty() && results.is_empty());
+        assert_eq!(runs(), 1);
+        assert!(current
+            .policy_statuses
+            .iter()
+            .flat_map(|policy| policy.details.iter())
+            .all(|detail| detail.new_compliance_state == "Compliant"));
+
+        // Change only
+        let mut current = statuses(vec![
+            policy("a", &[("linux_mount_where", "/mnt/a2")]),
+            policy("b", &[("linux_mount_where", "/mnt/b")]),
+        ]);
+        let (diff, _) = apply_changed(&extensions, account_id, &mut current, &cache).await?;
+        assert_eq!(diff.changed.len(), 1);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(runs(), 2);
+
+        // Remove only, the CSEs run to remove what is no longer assigned
+        let mut current = statuses(vec![policy("a", &[("linux_mount_where", "/mnt/a2")])]);
+        let (diff, _) = apply_changed(&extensions, account_id, &mut current, &cache).await?;
+        assert_eq!(diff.removed.len(), 1);
+        assert!(diff.added.is_empty() && diff.changed.is_empty());
+        assert_eq!(runs(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_scopes() {
+        let claims = URL_SAFE_NO_PAD.encode(
+            r#"{"aud":"00000003-0000-0000-c000-000000000000","scp":"User.Read Directory.Read.All","roles":["Application.Read.All"]}"#,
+        );
+        let token = format!("e30.{}.sig", claims);
+        assert_eq!(
+            token_scopes(&token),
+            Some(vec![
+                "User.Read".to_string(),
+                "Directory.Read.All".to_string(),
+                "Application.Read.All".to_string(),
+            ])
+        );
+        assert_eq!(token_scopes("not-a-jwt"), None);
+    }
+
+    #[test]
+    fn test_next_refresh_delay_seeded() {
+        let interval = Duration::from_secs(5400);
+        assert_eq!(
+            next_refresh_delay(interval, Some(42)),
+            next_refresh_delay(interval, Some(42))
+        );
+    }
+
+    #[test]
+    fn test_next_refresh_delay_bounds() {
+        let interval = Duration::from_secs(5400);
+        for seed in 0..100 {
+            let delay = next_refresh_delay(interval, Some(seed));
+            assert!(delay >= interval);
+            assert!(delay <= interval + Duration::from_secs(1800));
+        }
+    }
+
+    #[test]
+    fn test_next_refresh_delay_clamped() {
+        let delay = next_refresh_delay(Duration::from_secs(1), Some(0));
+        assert!(delay >= MIN_REFRESH_INTERVAL);
+        assert!(delay <= MIN_REFRESH_INTERVAL + Duration::from_secs(100));
+    }
+
+    #[test]
+    fn test_apply_lock() {
+        let first = apply_lock("tux@example.com");
+        assert!(Arc::ptr_eq(&first, &apply_lock("tux@example.com")));
+        assert!(!Arc::ptr_eq(&first, &apply_lock("geeko@example.com")));
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<StdMutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Ok(mut logs) = self.0.lock() {
+                logs.extend_from_slice(buf);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_request_retries() {
+        let http = PolicyHttpConfig {
+            timeout: 5,
+            retries: 2,
+            backoff: 1,
+        };
+        let attempts = StdMutex::new(0);
+        let attempt = || {
+            let n = match attempts.lock() {
+                Ok(mut attempts) => {
+                    *attempts += 1;
+                    *attempts
+                }
+                Err(_) => 0,
+            };
+            async move { n }
+        };
+
+        // Transport failures are retried until the request succeeds
+        let res = policy_request(&http, || async {
+            match attempt().await {
+                n if n < 3 => Err(MsalError::RequestFailed("connection refused".to_string())),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert!(matches!(res, Ok(3)));
+
+        // Requests rejected by the service are not retried
+        let res: Result<(), MsalError> = policy_request(&http, || async {
+            attempt().await;
+            Err(MsalError::GeneralFailure("403 Forbidden".to_string()))
+        })
+        .await;
+        assert!(matches!(res, Err(MsalError::GeneralFailure(_))));
+        assert_eq!(attempts.lock().map(|n| *n).ok(), Some(4));
+
+        // The retries are exhausted
+        let res: Result<(), MsalError> = policy_request(&http, || async {
+            attempt().await;
+            Err(MsalError::RequestFailed("connection refused".to_string()))
+        })
+        .await;
+        assert!(matches!(res, Err(MsalError::RequestFailed(_))));
+        assert_eq!(attempts.lock().map(|n| *n).ok(), Some(7));
+
+        // Throttled requests are retried, with the delay doubling each time
+        let start = Instant::now();
+        let http = PolicyHttpConfig {
+            backoff: 20,
+            ..http
+        };
+        let res = policy_request(&http, || async {
+            match attempt().await {
+                n if n < 10 => Err(MsalError::GeneralFailure(
+                    "429 Too Many Requests".to_string(),
+                )),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert!(matches!(res, Ok(10)));
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_http_status() {
+        let status = |e: MsalError| (http_status(&e), is_transient(&e));
+        assert_eq!(
+            status(MsalError::RequestFailed("403 Forbidden".to_string())),
+            (Some(403), false)
+        );
+        assert_eq!(
+            status(MsalError::GeneralFailure(
+                "503 Service Unavailable".to_string()
+            )),
+            (Some(503), true)
+        );
+        assert_eq!(
+            status(MsalError::RequestFailed("connection refused".to_string())),
+            (None, true)
+        );
+        assert_eq!(
+            status(MsalError::GeneralFailure(
+                "missing access_token".to_string()
+            )),
+            (None, false)
+        );
+    }
+
+    #[test]
+    fn test_is_unreachable() {
+        let unreachable = |msg: &str| is_unreachable(&MsalError::RequestFailed(msg.to_string()));
+        assert!(unreachable(
+            "error sending request for url (https://manage.microsoft.com/policies)"
+        ));
+        assert!(unreachable(
+            "reqwest::Error { kind: Request, url: \"https://manage.microsoft.com/\", source: TimedOut }"
+        ));
+        assert!(unreachable("Request timed out after 30 seconds"));
+        assert!(!unreachable("503 Service Unavailable"));
+        assert!(!unreachable("RelativeUrlWithoutBase"));
+        assert!(!unreachable(
+            "reqwest::Error { kind: Decode, source: Error(\"EOF\") }"
+        ));
+        assert!(!is_unreachable(&MsalError::GeneralFailure(
+            "error sending request".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_device_not_joined() -> Result<()> {
+        for (i, device_id) in ["", "00000000-0000-0000-0000-000000000000"]
+            .iter()
+            .enumerate()
+        {
+            let path = std::env::temp_dir().join(format!(
+                "himmelblau-not-joined-{}-{}.conf",
+                std::process::id(),
+                i
+            ));
+            // The ODC provider is unreachable, so any Graph request would fail
+            let config_data = format!(
+                "[global]\nodc_provider = 127.0.0.1:9\n\
+                [example.com]\nintune_device_id = {}\n",
+                device_id
+            );
+            fs::write(&path, config_data).await?;
+            let config = HimmelblauConfig::new(path.to_str());
+            fs::remove_file(&path).await?;
+            let config = config.map_err(|e| anyhow!(e))?;
+
+            let res = apply_intune_policy(&config, "tux@example.com", "graph", "intune").await;
+            assert!(res
+                .err()
+                .is_some_and(|e| e.downcast_ref::<DeviceNotJoined>().is_some()));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_enrolled_device() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "himmelblau-enrolled-device-{}.conf",
+            std::process::id()
+        ));
+        let config_data = "[global]\ndomains = example.com\n\
+            [example.com]\n\
+            tenant_id = 00000000-0000-0000-0000-000000000001\n\
+            domain_aliases = example.org\n\
+            intune_device_id = 00000000-0000-0000-0000-000000000002\n\
+            [example.net]\n\
+            intune_device_id = 00000000-0000-0000-0000-000000000000\n";
+        fs::write(&path, config_data).await?;
+        let config = HimmelblauConfig::new(path.to_str());
+        fs::remove_file(&path).await?;
+        let config = config.map_err(|e| anyhow!(e))?;
+
+        let device = Some((
+            "example.com".to_string(),
+            "00000000-0000-0000-0000-000000000002".to_string(),
+        ));
+        assert_eq!(
+            enrolled_device(&config, "tux@example.com", false).ok(),
+            Some(device.clone())
+        );
+        // An alias uses the enrollment of its tenant
+        assert_eq!(
+            enrolled_device(&config, "tux@example.org", false).ok(),
+            Some(device)
+        );
+        assert!(enrolled_device(&config, "tux@example.net", false)
+            .err()
+            .is_some_and(|e| e.downcast_ref::<DeviceNotJoined>().is_some()));
+        assert!(enrolled_device(&config, "tux@example.edu", false).is_ok_and(|d| d.is_none()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_policies() -> Result<()> {
+        let cache = MemorySnapshotCache::default();
+        let account_id = "tux@example.com";
+        let config = HimmelblauConfig::new(None).map_err(|e| anyhow!(e))?;
+        let res = prefetch_policies(&config, account_id, "graph", "intune", &cache).await;
+        assert!(res.is_err_and(|e| e.to_string() == "Device is not enrolled in Intune"));
+        assert!(cache.load_progress(account_id).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cached_policies() -> Result<()> {
+        let cache = MemorySnapshotCache::default();
+        let account_id = "tux@example.com";
+        let res = cached_policies(&cache, account_id, None, 0).await;
+        assert!(res.is_err_and(|e| e.to_string().contains("no policies were applied")));
+
+        // Prefetched policies are used until a snapshot was applied
+        let statuses = |policy_id: &str| IntuneStatus {
+            device_id: Some("device".to_string()),
+            policy_statuses: vec![policy(policy_id, &[("linux_mount_where", "/mnt")])],
+        };
+        cache
+            .store_progress(account_id, &statuses("prefetched"), &[])
+            .await?;
+        let cached = cached_policies(&cache, account_id, None, 0).await?;
+        assert_eq!(cached.policy_statuses[0].policy_id, "prefetched");
+        cache.store(account_id, &statuses("applied")).await?;
+        let cached = cached_policies(&cache, account_id, None, 0).await?;
+        assert_eq!(cached.policy_statuses[0].policy_id, "applied");
+
+        // Without a fetch time the policies expire as soon as a max age is set
+        let res = cached_policies(&cache, account_id, Some(3600), 1000).await;
+        assert!(res.is_err_and(|e| e.to_string().contains("older than 3600 seconds")));
+
+        cache.store_fetched(account_id, 1000).await?;
+        assert!(cached_policies(&cache, account_id, Some(3600), 4600)
+            .await
+            .is_ok());
+        let res = cached_policies(&cache, account_id, Some(3600), 4601).await;
+        assert!(res.is_err_and(|e| e.to_string().contains("older than 3600 seconds")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fallback_policies() -> Result<()> {
+        let cache = MemorySnapshotCache::default();
+        let account_id = "tux@example.com";
+        let unreachable = MsalError::RequestFailed("error sending request".to_string());
+        assert!(fallback_policies(&cache, account_id, None, 0, &unreachable)
+            .await
+            .is_none());
+
+        // The last applied snapshot is enforced while the service is unreachable
+        cache
+            .store(
+                account_id,
+                &IntuneStatus {
+                    device_id: Some("device".to_string()),
+                    policy_statuses: vec![policy("applied", &[("linux_mount_where", "/mnt")])],
+                },
+            )
+            .await?;
+        cache.store_fetched(account_id, 1000).await?;
+        assert!(
+            fallback_policies(&cache, account_id, Some(3600), 2000, &unreachable)
+                .await
+                .is_some_and(|statuses| statuses.policy_statuses[0].policy_id == "applied")
+        );
+        assert!(
+            fallback_policies(&cache, account_id, Some(3600), 5000, &unreachable)
+                .await
+                .is_none()
+        );
+
+        // A response from the service is not a reason to fall back
+        let rejected = MsalError::RequestFailed("403 Forbidden".to_string());
+        assert!(fallback_policies(&cache, account_id, None, 0, &rejected)
+            .await
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_graph_for_domain() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("himmelblau-graph-{}.conf", std::process::id()));
+        let config_data = "[example.com]\n\
+            tenant_id = 00000000-0000-0000-0000-000000000001\n\
+            graph_url = https://graph.microsoft.com\n\
+            [example.org]\n\
+            tenant_id = 00000000-0000-0000-0000-000000000002\n\
+            graph_url = https://graph.microsoft.us\n";
+        fs::write(&path, config_data).await?;
+        let config = HimmelblauConfig::new(path.to_str());
+        fs::remove_file(&path).await?;
+        let config = config.map_err(|e| anyhow!(e))?;
+
+        let com = graph_for_domain(&config, "example.com")
+            .await
+            .map_err(|e| msal_error(&e, &[]))?;
+        let com_again = graph_for_domain(&config, "EXAMPLE.com")
+            .await
+            .map_err(|e| msal_error(&e, &[]))?;
+        let org = graph_for_domain(&config, "example.org")
+            .await
+            .map_err(|e| msal_error(&e, &[]))?;
+        assert!(Arc::ptr_eq(&com, &com_again));
+        assert!(!Arc::ptr_eq(&com, &org));
+        assert_eq!(
+            org.graph_url().await.map_err(|e| msal_error(&e, &[]))?,
+            "https://graph.microsoft.us"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tokens_redacted() {
+        let sentinel = "SENTINEL-ACCESS-TOKEN";
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // Nothing listens on the discard port, so the request fails
+        let res = match Graph::new(
+            "",
+            "example.com",
+            Some("127.0.0.1:9"),
+            Some("00000000-0000-0000-0000-000000000000"),
+            Some("http://127.0.0.1:9"),
+        )
+        .await
+        {
+            Ok(graph) => graph.intune_service_endpoints(sentinel).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        let err = res.err().map(|e| msal_error(&e, &[sentinel]));
+        assert!(err.is_some());
+        error!("{:?}", err);
+        assert!(!format!("{:?}", err).contains(sentinel));
+
+        let err = msal_error(
+            &MsalError::GeneralFailure(format!("Bearer {}", sentinel)),
+            &[sentinel],
+        );
+        error!("{:?}", err);
+        assert_eq!(err.to_string(), "General failure: Bearer [REDACTED]");
+
+        let logs = logs.0.lock().map(|logs| logs.clone()).unwrap_or_default();
+        let logs = String::from_utf8_lossy(&logs);
+        assert!(logs.contains("[REDACTED]"));
+        assert!(!logs.contains(sentinel));
     }
 }
@@ -0,0 +1,198 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Resolves the settings of all assigned policies to a single value per key
+ * (a dconf key, an sshd directive, a kernel parameter), for the Client Side
+ * Extensions which merge several policies into one file.
+ */
+use anyhow::Result;
+use himmelblau::intune::IntuneStatus;
+use std::collections::BTreeMap;
+use tracing::{error, warn};
+
+const NON_COMPLIANT: &str = "NonCompliant";
+
+/// A value, and the policy setting it was taken from.
+pub struct Resolved<T> {
+    pub value: T,
+    /// The index of the policy, and of the setting within the policy.
+    pub policy: usize,
+    pub detail: usize,
+    /// The settings of other policies for the same key which this one
+    /// takes precedence over, by policy index and setting index.
+    pub overridden: Vec<(usize, usize)>,
+}
+
+/// Report the `overridden` settings non-compliant, with the value of the
+/// setting which took precedence over them (at `winner`) as their actual
+/// value.
+pub fn mark_overridden(
+    policies: &mut IntuneStatus,
+    winner: (usize, usize),
+    overridden: &[(usize, usize)],
+) {
+    let actual = policies
+        .policy_statuses
+        .get(winner.0)
+        .and_then(|policy| policy.details.get(winner.1))
+        .map(|detail| detail.expected_value.clone())
+        .unwrap_or_default();
+    for (p, d) in overridden {
+        if let Some(detail) = policies
+            .policy_statuses
+            .get_mut(*p)
+            .and_then(|policy| policy.details.get_mut(*d))
+        {
+            detail.actual_value = actual.clone();
+            detail.new_compliance_state = NON_COMPLIANT.to_string();
+        }
+    }
+}
+
+/// Collect the settings of all policies whose id starts with `prefix` by key.
+/// `parse` is passed the rest of the setting id and the expected value, and
+/// returns the key and value, or None to skip the setting. Policies are
+/// ordered by id, and when several set the same key, the policy with the
+/// greatest id takes precedence (Intune does not tell the device when a
+/// policy was modified). Settings which `parse` rejects are added to
+/// `errors`. `kind` names a key in log messages, e.g. "sshd directive".
+pub fn resolve<T, F>(
+    policies: &IntuneStatus,
+    prefix: &str,
+    kind: &str,
+    errors: &mut Vec<String>,
+    parse: F,
+) -> BTreeMap<String, Resolved<T>>
+where
+    F: Fn(&str, &str) -> Result<Option<(String, T)>>,
+{
+    let mut resolved: BTreeMap<String, Resolved<T>> = BTreeMap::new();
+    for (p, policy) in policies.policy_statuses.iter().enumerate() {
+        for (d, detail) in policy.details.iter().enumerate() {
+            let name = match detail.setting_definition_item_id.strip_prefix(prefix) {
+                Some(name) => name,
+                None => continue,
+            };
+            let (key, value) = match parse(name, &detail.expected_value) {
+                Ok(Some(setting)) => setting,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!(
+                        "Skipping setting {} of policy {}: {:?}",
+                        detail.setting_definition_item_id, policy.policy_id, e
+                    );
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                    continue;
+                }
+            };
+            let mut overridden = vec![];
+            if let Some(previous) = resolved.remove(&key) {
+                if let Some(prev) = policies.policy_statuses.get(previous.policy) {
+                    warn!(
+                        "{} {} is set by policies {} and {}, using policy {} and reporting \
+                        the setting of policy {} non-compliant",
+                        kind,
+                        key,
+                        prev.policy_id,
+                        policy.policy_id,
+                        policy.policy_id,
+                        prev.policy_id
+                    );
+                }
+                overridden = previous.overridden;
+                overridden.push((previous.policy, previous.detail));
+            }
+            resolved.insert(
+                key,
+                Resolved {
+                    value,
+                    policy: p,
+                    detail: d,
+                    overridden,
+                },
+            );
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_resolve() {
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy(
+                    "a",
+                    &[
+                        ("linux_test_one", "1"),
+                        ("linux_test_two", "2"),
+                        ("linux_other_one", "x"),
+                    ],
+                ),
+                policy(
+                    "b",
+                    &[
+                        ("linux_test_one", "3"),
+                        ("linux_test_bad", "x"),
+                        ("linux_test_skip", "4"),
+                    ],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let resolved = resolve(
+            &policies,
+            "linux_test_",
+            "test key",
+            &mut errors,
+            |name, value| match name {
+                "skip" => Ok(None),
+                _ => Ok(Some((
+                    name.to_string(),
+                    value.parse::<u32>().map_err(|e| anyhow!(e))?,
+                ))),
+            },
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            resolved
+                .iter()
+                .map(|(key, r)| (key.as_str(), r.value, r.policy, r.detail))
+                .collect::<Vec<_>>(),
+            vec![("one", 3, 1, 0), ("two", 2, 0, 1)]
+        );
+        let one = resolved.get("one").map(|r| r.overridden.clone());
+        assert_eq!(one, Some(vec![(0, 0)]));
+
+        let mut policies = policies;
+        mark_overridden(&mut policies, (1, 0), &[(0, 0)]);
+        let detail = policies
+            .policy_statuses
+            .first()
+            .and_then(|policy| policy.details.first());
+        assert!(detail
+            .is_some_and(|detail| detail.actual_value == "3"
+                && detail.new_compliance_state == "NonCompliant"));
+    }
+}
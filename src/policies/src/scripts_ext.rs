@@ -16,6 +16,7 @@
    along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 use crate::cse::CSE;
+use crate::files::{install_file, install_user_file};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use base64::engine::general_purpose::STANDARD;
@@ -24,11 +25,11 @@ use himmelblau::intune::{IntuneStatus, PolicyStatus};
 use himmelblau_unix_common::config::HimmelblauConfig;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tracing::debug;
+use tracing::{debug, error, info};
 
 /// A simple persistent cache mapping usernames to the set of applied policy IDs.
 #[derive(Serialize, Deserialize, Default)]
@@ -83,6 +84,14 @@ impl CSE for ScriptsCSE {
         }
     }
 
+    fn name(&self) -> &'static str {
+        "scripts"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &["linux_customconfig_"]
+    }
+
     async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
         // Generate the persistent cache path.
         let cache_path_str = self
@@ -116,7 +125,9 @@ impl CSE for ScriptsCSE {
             let _ = fs::remove_file(&wrapper_file).await;
         }
 
-        // Process and apply the changed policies.
+        // Process and apply the changed policies. A policy which fails to
+        // apply is skipped, so it doesn't prevent applying the others.
+        let mut errors = vec![];
         for policy in policies.policy_statuses.iter_mut() {
             // Validate this is a scripts policy
             if policy
@@ -124,7 +135,10 @@ impl CSE for ScriptsCSE {
                 .iter()
                 .any(|d| d.setting_definition_item_id == "linux_customconfig_script")
             {
-                self.apply_policy(policy).await?;
+                if let Err(e) = self.apply_policy(policy).await {
+                    error!("Skipping script policy {}: {:?}", policy.policy_id, e);
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                }
             }
         }
 
@@ -135,10 +149,48 @@ impl CSE for ScriptsCSE {
             .await
             .map_err(|e| anyhow!("Failed to save policy cache: {}", e))?;
 
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply script policies: {}",
+                errors.join("; ")
+            ));
+        }
         Ok(true)
     }
 }
 
+/// The interpreter a script is run with: the command in its #! line, or the
+/// program for `#!/usr/bin/env program`. Scripts without a #! line are run by
+/// bash, from the wrapper script.
+fn script_interpreter(script: &str) -> String {
+    let shebang = match script
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("#!"))
+    {
+        Some(shebang) => shebang,
+        None => return "/bin/bash".to_string(),
+    };
+    let mut args = shebang.split_whitespace();
+    match args.next() {
+        Some(cmd) if cmd == "env" || cmd.ends_with("/env") => args
+            .find(|arg| !arg.starts_with('-') && !arg.contains('='))
+            .unwrap_or(cmd)
+            .to_string(),
+        Some(cmd) => cmd.to_string(),
+        None => "/bin/bash".to_string(),
+    }
+}
+
+fn interpreter_allowed(interpreter: &str, allowed: &[String]) -> bool {
+    allowed.iter().any(|entry| {
+        entry == interpreter
+            // Programs run via env are matched by file name
+            || (!interpreter.contains('/')
+                && Path::new(entry).file_name().is_some_and(|name| name == interpreter))
+    })
+}
+
 impl ScriptsCSE {
     async fn script_path(&self) -> Result<String> {
         let db_path = self.config.get_db_path();
@@ -166,6 +218,33 @@ impl ScriptsCSE {
             .ok_or(anyhow!("Failed to convert cache path to string"))
     }
 
+    /// Install a script readable only by the user it is executed as, since
+    /// scripts may embed secrets.
+    async fn install_script(
+        &self,
+        path: &str,
+        contents: &str,
+        execution_context: &str,
+    ) -> Result<()> {
+        let path = Path::new(path);
+        match execution_context {
+            "root" => install_file(path, contents.as_bytes(), 0o700, None).await,
+            _ => {
+                install_user_file(
+                    &self.config,
+                    path,
+                    contents.as_bytes(),
+                    0o700,
+                    &self.username,
+                    None,
+                )
+                .await
+            }
+        }
+        .map_err(|e| anyhow!("Failed to install script {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
     async fn apply_policy(&self, policy: &mut PolicyStatus) -> Result<()> {
         let mut execution_context = "root".to_string();
         let mut frequency = "1hour".to_string();
@@ -216,6 +295,29 @@ impl ScriptsCSE {
             }
         }
 
+        // Admins may force a mis-scoped policy to run as root or as the user
+        if let Some(context) = self
+            .config
+            .get_script_execution_context_overrides()
+            .get(&policy.policy_id)
+        {
+            match context.as_str() {
+                "root" => execution_context = "root".to_string(),
+                "user" => execution_context = self.username.to_string(),
+                _ => {
+                    return Err(anyhow!(
+                        "Unrecognized execution context override '{}' for policy {}",
+                        context,
+                        policy.policy_id
+                    ))
+                }
+            }
+            info!(
+                "Overriding execution context of policy {} to '{}'",
+                policy.policy_id, context
+            );
+        }
+
         let script_directory = self
             .script_path()
             .await
@@ -232,21 +334,28 @@ impl ScriptsCSE {
         let script_content = String::from_utf8(script_bytes)
             .map_err(|e| anyhow!("Failed to convert script to utf8 string: {}", e))?;
 
+        if let Some(allowed) = self.config.get_allowed_script_interpreters() {
+            let interpreter = script_interpreter(&script_content);
+            if !interpreter_allowed(&interpreter, &allowed) {
+                for detail in policy.details.iter_mut() {
+                    if detail.setting_definition_item_id == "linux_customconfig_script" {
+                        detail.actual_value = String::new();
+                        detail.new_compliance_state = "Error".to_string();
+                    }
+                }
+                // Stop running a previously installed version of the script
+                let _ = fs::remove_file(format!("/etc/cron.d/policy_{}", policy.policy_id)).await;
+                return Err(anyhow!(
+                    "Rejected script with interpreter '{}', which is not in allowed_script_interpreters",
+                    interpreter
+                ));
+            }
+        }
+
         let script_file_path =
             format!("{}/policy_{}_script.sh", script_directory, policy.policy_id);
-        let mut script_file = fs::File::create(&script_file_path)
-            .await
-            .map_err(|e| anyhow!("Failed to create script file: {}", e))?;
-        script_file
-            .write_all(script_content.as_bytes())
-            .await
-            .map_err(|e| anyhow!("Failed to write script to file: {}", e))?;
-        Command::new("chmod")
-            .arg("+x")
-            .arg(&script_file_path)
-            .output()
-            .await
-            .map_err(|e| anyhow!("Failed to set script permissions: {}", e))?;
+        self.install_script(&script_file_path, &script_content, &execution_context)
+            .await?;
 
         let wrapper_script_path = format!(
             "{}/policy_{}_wrapper.sh",
@@ -269,24 +378,8 @@ exit $exit_code
 "#,
             retries, script_file_path
         );
-        let mut wrapper_script_file =
-            fs::File::create(&wrapper_script_path).await.map_err(|e| {
-                anyhow!(
-                    "Failed to create wrapper file {}: {}",
-                    wrapper_script_path,
-                    e
-                )
-            })?;
-        wrapper_script_file
-            .write_all(wrapper_script.as_bytes())
-            .await
-            .map_err(|e| anyhow!("Failed to write wrapper script to file: {}", e))?;
-        Command::new("chmod")
-            .arg("+x")
-            .arg(&wrapper_script_path)
-            .output()
-            .await
-            .map_err(|e| anyhow!("Failed to set wrapper script permissions: {}", e))?;
+        self.install_script(&wrapper_script_path, &wrapper_script, &execution_context)
+            .await?;
 
         let cron_schedule = match frequency.as_str() {
             "15" => "*/15 * * * *",  // Every 15 minutes
@@ -336,3 +429,29 @@ exit $exit_code
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_interpreter() {
+        assert_eq!(script_interpreter("#!/bin/sh\necho"), "/bin/sh");
+        assert_eq!(
+            script_interpreter("#! /usr/bin/python3 -u\n"),
+            "/usr/bin/python3"
+        );
+        assert_eq!(
+            script_interpreter("#!/usr/bin/env -S PYTHONUNBUFFERED=1 python3\n"),
+            "python3"
+        );
+        assert_eq!(script_interpreter("echo hello\n"), "/bin/bash");
+
+        let allowed = vec!["/bin/sh".to_string(), "/usr/bin/python3".to_string()];
+        assert!(interpreter_allowed("/bin/sh", &allowed));
+        assert!(interpreter_allowed("python3", &allowed));
+        assert!(!interpreter_allowed("/bin/bash", &allowed));
+        assert!(!interpreter_allowed("/tmp/python3", &allowed));
+        assert!(!interpreter_allowed("perl", &allowed));
+    }
+}
@@ -0,0 +1,514 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Tracks the last applied set of Intune policies, so that each refresh can
+ * report exactly which settings were added, removed or changed.
+ */
+use crate::files::install_file;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::{IntuneStatus, PolicyStatus};
+use himmelblau_unix_common::config::HimmelblauConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SettingKey {
+    pub policy_id: String,
+    pub setting_definition_item_id: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PolicyDiff {
+    /// Newly assigned settings and their expected value.
+    pub added: BTreeMap<SettingKey, String>,
+    /// Settings which are no longer assigned and their previous value.
+    pub removed: BTreeMap<SettingKey, String>,
+    /// Settings whose expected value changed, as (old, new).
+    pub changed: BTreeMap<SettingKey, (String, String)>,
+}
+
+impl PolicyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Lists the settings which changed, without their values, since those may
+/// contain secrets (e.g. Wi-Fi passphrases).
+impl fmt::Display for PolicyDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let changes = [
+            ("added", self.added.keys().collect::<Vec<_>>()),
+            ("removed", self.removed.keys().collect()),
+            ("changed", self.changed.keys().collect()),
+        ];
+        for (change, keys) in changes {
+            for key in keys {
+                writeln!(
+                    f,
+                    "{} {}/{}",
+                    change, key.policy_id, key.setting_definition_item_id
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Order policies by id, and their settings by definition item id, so that CSE
+/// output and saved snapshots do not depend on the order Intune returned them.
+pub fn sort_policies(policies: &mut [PolicyStatus]) {
+    policies.sort_by(|a, b| a.policy_id.cmp(&b.policy_id));
+    for policy in policies.iter_mut() {
+        policy.details.sort_by(|a, b| {
+            a.setting_definition_item_id
+                .cmp(&b.setting_definition_item_id)
+        });
+    }
+}
+
+fn flatten(policies: &[PolicyStatus]) -> BTreeMap<SettingKey, String> {
+    policies
+        .iter()
+        .flat_map(|policy| {
+            policy.details.iter().map(|detail| {
+                (
+                    SettingKey {
+                        policy_id: policy.policy_id.clone(),
+                        setting_definition_item_id: detail.setting_definition_item_id.clone(),
+                    },
+                    detail.expected_value.clone(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Compare two policy snapshots, keyed by policy id and setting definition
+/// item id.
+pub fn diff_snapshots(old: &[PolicyStatus], new: &[PolicyStatus]) -> PolicyDiff {
+    let old = flatten(old);
+    let new = flatten(new);
+    let mut diff = PolicyDiff::default();
+
+    for (key, new_value) in new.iter() {
+        match old.get(key) {
+            Some(old_value) if old_value != new_value => {
+                diff.changed
+                    .insert(key.clone(), (old_value.clone(), new_value.clone()));
+            }
+            Some(_) => {}
+            None => {
+                diff.added.insert(key.clone(), new_value.clone());
+            }
+        }
+    }
+    for (key, old_value) in old.into_iter() {
+        if !new.contains_key(&key) {
+            diff.removed.insert(key, old_value);
+        }
+    }
+    diff
+}
+
+pub fn snapshot_path(config: &HimmelblauConfig, account_id: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::from(config.get_db_path());
+    if !path.pop() {
+        return Err(anyhow!("Failed to determine policy snapshot path"));
+    }
+    path.push(format!("policy_snapshot_{}.json", account_id));
+    Ok(path)
+}
+
+/// Loads the last applied snapshot. If none has been saved, returns None.
+pub async fn load_snapshot(path: &Path) -> Result<Option<IntuneStatus>> {
+    match fs::read_to_string(path).await {
+        Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Snapshots contain the expected value of every setting, which may include
+/// secrets, so they are readable by root only.
+pub async fn save_snapshot(path: &Path, snapshot: &IntuneStatus) -> Result<()> {
+    let data = serde_json::to_string_pretty(snapshot)?;
+    install_file(path, data.as_bytes(), 0o600, None).await?;
+    Ok(())
+}
+
+/// The progress of an apply which did not complete, e.g. because the device
+/// lost power part way through.
+#[derive(Deserialize)]
+pub struct ApplyProgress {
+    /// The policies being applied, with the compliance state set so far.
+    pub statuses: IntuneStatus,
+    /// The names of the CSEs which completed successfully.
+    pub completed: Vec<String>,
+}
+
+fn progress_json(statuses: &IntuneStatus, completed: &[String]) -> Result<String> {
+    Ok(serde_json::to_string(&serde_json::json!({
+        "statuses": statuses,
+        "completed": completed,
+    }))?)
+}
+
+/// Storage for the last applied snapshot of each account.
+#[async_trait]
+pub trait SnapshotCache: Send + Sync {
+    /// Load the last applied snapshot. If none has been saved, returns None.
+    async fn load(&self, account_id: &str) -> Result<Option<IntuneStatus>>;
+    async fn store(&self, account_id: &str, snapshot: &IntuneStatus) -> Result<()>;
+    /// Load the progress of an interrupted apply. Caches which do not record
+    /// progress always return None, so every apply starts from scratch.
+    async fn load_progress(&self, _account_id: &str) -> Result<Option<ApplyProgress>> {
+        Ok(None)
+    }
+    /// Record that the `completed` CSEs have applied `statuses`.
+    async fn store_progress(
+        &self,
+        _account_id: &str,
+        _statuses: &IntuneStatus,
+        _completed: &[String],
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Forget the progress once an apply has completed.
+    async fn clear_progress(&self, _account_id: &str) -> Result<()> {
+        Ok(())
+    }
+    /// When the policies were last fetched from the policy service, as a unix
+    /// timestamp. Caches which do not record it always return None.
+    async fn load_fetched(&self, _account_id: &str) -> Result<Option<i64>> {
+        Ok(None)
+    }
+    async fn store_fetched(&self, _account_id: &str, _fetched: i64) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Stores snapshots as JSON files beside the cache database.
+pub struct FileSnapshotCache {
+    config: HimmelblauConfig,
+}
+
+impl FileSnapshotCache {
+    pub fn new(config: &HimmelblauConfig) -> Self {
+        FileSnapshotCache {
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotCache for FileSnapshotCache {
+    async fn load(&self, account_id: &str) -> Result<Option<IntuneStatus>> {
+        load_snapshot(&snapshot_path(&self.config, account_id)?).await
+    }
+
+    async fn store(&self, account_id: &str, snapshot: &IntuneStatus) -> Result<()> {
+        save_snapshot(&snapshot_path(&self.config, account_id)?, snapshot).await
+    }
+
+    async fn load_progress(&self, account_id: &str) -> Result<Option<ApplyProgress>> {
+        match fs::read_to_string(self.progress_path(account_id)?).await {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn store_progress(
+        &self,
+        account_id: &str,
+        statuses: &IntuneStatus,
+        completed: &[String],
+    ) -> Result<()> {
+        install_file(
+            &self.progress_path(account_id)?,
+            progress_json(statuses, completed)?.as_bytes(),
+            0o600,
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_progress(&self, account_id: &str) -> Result<()> {
+        let path = self.progress_path(account_id)?;
+        if path.exists() {
+            fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn load_fetched(&self, account_id: &str) -> Result<Option<i64>> {
+        match fs::read_to_string(self.fetched_path(account_id)?).await {
+            Ok(data) => Ok(Some(data.trim().parse()?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn store_fetched(&self, account_id: &str, fetched: i64) -> Result<()> {
+        fs::write(self.fetched_path(account_id)?, fetched.to_string()).await?;
+        Ok(())
+    }
+}
+
+impl FileSnapshotCache {
+    fn progress_path(&self, account_id: &str) -> Result<PathBuf> {
+        let mut path = snapshot_path(&self.config, account_id)?;
+        path.set_file_name(format!("policy_progress_{}.json", account_id));
+        Ok(path)
+    }
+
+    fn fetched_path(&self, account_id: &str) -> Result<PathBuf> {
+        let mut path = snapshot_path(&self.config, account_id)?;
+        path.set_file_name(format!("policy_fetched_{}", account_id));
+        Ok(path)
+    }
+}
+
+/// Copy the applied value and compliance state of each setting from a
+/// previously applied snapshot.
+pub fn restore_compliance(previous: &IntuneStatus, statuses: &mut IntuneStatus) {
+    for policy in statuses.policy_statuses.iter_mut() {
+        let prev = match previous
+            .policy_statuses
+            .iter()
+            .find(|prev| prev.policy_id == policy.policy_id)
+        {
+            Some(prev) => prev,
+            None => continue,
+        };
+        for detail in policy.details.iter_mut() {
+            if let Some(prev) = prev
+                .details
+                .iter()
+                .find(|prev| prev.setting_definition_item_id == detail.setting_definition_item_id)
+            {
+                detail.actual_value = prev.actual_value.clone();
+                detail.new_compliance_state = prev.new_compliance_state.clone();
+            }
+        }
+    }
+}
+
+/// Compare the policies about to be applied with the last applied snapshot.
+/// Returns None if no snapshot has been saved.
+pub async fn diff_last_applied(
+    cache: &dyn SnapshotCache,
+    account_id: &str,
+    statuses: &IntuneStatus,
+) -> Result<Option<PolicyDiff>> {
+    Ok(cache
+        .load(account_id)
+        .await?
+        .map(|previous| diff_snapshots(&previous.policy_statuses, &statuses.policy_statuses)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use himmelblau::intune::PolicyDetails;
+
+    pub(crate) fn policy(policy_id: &str, settings: &[(&str, &str)]) -> PolicyStatus {
+        PolicyStatus {
+            policy_id: policy_id.to_string(),
+            last_status_date_time: String::new(),
+            details: settings
+                .iter()
+                .map(|(id, value)| PolicyDetails {
+                    rule_id: String::new(),
+                    setting_definition_item_id: id.to_string(),
+                    expected_value: value.to_string(),
+                    actual_value: String::new(),
+                    error_type: None,
+                    error_code: None,
+                    new_compliance_state: "Error".to_string(),
+                    old_compliance_state: "Unknown".to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn key(policy_id: &str, setting: &str) -> SettingKey {
+        SettingKey {
+            policy_id: policy_id.to_string(),
+            setting_definition_item_id: setting.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let old = vec![
+            policy("a", &[("linux_customconfig_script", "ZWNobw==")]),
+            policy("b", &[("linux_deviceencryption_required", "true")]),
+        ];
+        let new = vec![
+            policy(
+                "a",
+                &[
+                    ("linux_customconfig_script", "bHM="),
+                    ("linux_customconfig_executioncontext", "root"),
+                ],
+            ),
+            policy("c", &[("linux_passwordpolicy_minimumlength", "8")]),
+        ];
+
+        let diff = diff_snapshots(&old, &new);
+        assert_eq!(
+            diff.added.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                key("a", "linux_customconfig_executioncontext"),
+                key("c", "linux_passwordpolicy_minimumlength"),
+            ]
+        );
+        assert_eq!(
+            diff.removed.keys().cloned().collect::<Vec<_>>(),
+            vec![key("b", "linux_deviceencryption_required")]
+        );
+        assert_eq!(
+            diff.changed.get(&key("a", "linux_customconfig_script")),
+            Some(&("ZWNobw==".to_string(), "bHM=".to_string()))
+        );
+        assert!(diff_snapshots(&new, &new).is_empty());
+
+        // Values are never displayed
+        assert_eq!(
+            diff.to_string(),
+            "added a/linux_customconfig_executioncontext\n\
+            added c/linux_passwordpolicy_minimumlength\n\
+            removed b/linux_deviceencryption_required\n\
+            changed a/linux_customconfig_script\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_snapshot() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("himmelblau-snapshot-{}.json", std::process::id()));
+        let snapshot = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![policy("a", &[("linux_wifi_psk", "secret")])],
+        };
+        save_snapshot(&path, &snapshot).await?;
+        let mode = fs::metadata(&path).await?.permissions().mode() & 0o777;
+        let loaded = load_snapshot(&path).await?;
+        fs::remove_file(&path).await?;
+        assert_eq!(mode, 0o600);
+        assert!(loaded.is_some_and(|loaded| loaded.policy_statuses.len() == 1));
+        Ok(())
+    }
+
+    #[derive(Default)]
+    pub(crate) struct MemorySnapshotCache {
+        snapshots: std::sync::Mutex<BTreeMap<String, String>>,
+    }
+
+    #[async_trait]
+    impl SnapshotCache for MemorySnapshotCache {
+        async fn load(&self, account_id: &str) -> Result<Option<IntuneStatus>> {
+            let snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            match snapshots.get(account_id) {
+                Some(data) => Ok(Some(serde_json::from_str(data)?)),
+                None => Ok(None),
+            }
+        }
+
+        async fn store(&self, account_id: &str, snapshot: &IntuneStatus) -> Result<()> {
+            let data = serde_json::to_string(snapshot)?;
+            let mut snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            snapshots.insert(account_id.to_string(), data);
+            Ok(())
+        }
+
+        async fn load_progress(&self, account_id: &str) -> Result<Option<ApplyProgress>> {
+            let snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            match snapshots.get(&format!("progress:{}", account_id)) {
+                Some(data) => Ok(Some(serde_json::from_str(data)?)),
+                None => Ok(None),
+            }
+        }
+
+        async fn store_progress(
+            &self,
+            account_id: &str,
+            statuses: &IntuneStatus,
+            completed: &[String],
+        ) -> Result<()> {
+            let data = progress_json(statuses, completed)?;
+            let mut snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            snapshots.insert(format!("progress:{}", account_id), data);
+            Ok(())
+        }
+
+        async fn clear_progress(&self, account_id: &str) -> Result<()> {
+            let mut snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            snapshots.remove(&format!("progress:{}", account_id));
+            Ok(())
+        }
+
+        async fn load_fetched(&self, account_id: &str) -> Result<Option<i64>> {
+            let snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            match snapshots.get(&format!("fetched:{}", account_id)) {
+                Some(data) => Ok(Some(data.parse()?)),
+                None => Ok(None),
+            }
+        }
+
+        async fn store_fetched(&self, account_id: &str, fetched: i64) -> Result<()> {
+            let mut snapshots = self.snapshots.lock().map_err(|e| anyhow!("{}", e))?;
+            snapshots.insert(format!("fetched:{}", account_id), fetched.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diff_last_applied() {
+        let cache = MemorySnapshotCache::default();
+        let statuses = |policies: Vec<PolicyStatus>| IntuneStatus {
+            device_id: Some("device".to_string()),
+            policy_statuses: policies,
+        };
+        let old = statuses(vec![policy("a", &[("linux_mount_where", "/mnt/a")])]);
+        let new = statuses(vec![policy("b", &[("linux_mount_where", "/mnt/b")])]);
+
+        assert!(matches!(
+            diff_last_applied(&cache, "tux@example.com", &old).await,
+            Ok(None)
+        ));
+        assert!(cache.store("tux@example.com", &old).await.is_ok());
+
+        let diff = diff_last_applied(&cache, "tux@example.com", &new).await;
+        assert!(diff.is_ok_and(|diff| diff.is_some_and(|diff| {
+            diff.removed.contains_key(&key("a", "linux_mount_where"))
+                && diff.added.contains_key(&key("b", "linux_mount_where"))
+        })));
+        // Snapshots are kept per account
+        assert!(matches!(
+            diff_last_applied(&cache, "other@example.com", &new).await,
+            Ok(None)
+        ));
+    }
+}
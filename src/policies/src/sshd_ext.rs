@@ -0,0 +1,327 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{forget_provenance, install_file, provenance_registry, Provenance};
+use crate::resolve::{mark_overridden, resolve, Resolved};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::IntuneStatus;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, error, warn};
+
+const SETTING_PREFIX: &str = "linux_sshd_";
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+// sshd uses the first value it reads for a directive, and reads the drop-ins
+// in lexical order, so this drop-in sorts first
+const SSHD_DROPIN: &str = "/etc/ssh/sshd_config.d/00-himmelblau.conf";
+// The unit is named ssh.service on Debian and its derivatives
+const SSHD_UNITS: &[&str] = &["sshd.service", "ssh.service"];
+
+/// Collect the directives set by all policies. Each linux_sshd_* setting
+/// sets the directive named by the rest of its id (case insensitively), which
+/// must be in `allowed`, e.g. linux_sshd_passwordauthentication = no.
+/// Settings which are refused are added to `errors`.
+fn resolve_directives(
+    policies: &IntuneStatus,
+    allowed: &[String],
+    errors: &mut Vec<String>,
+) -> BTreeMap<String, Resolved<String>> {
+    resolve(
+        policies,
+        SETTING_PREFIX,
+        "sshd directive",
+        errors,
+        |key, value| {
+            let value = value.trim();
+            match allowed.iter().find(|name| name.eq_ignore_ascii_case(key)) {
+                None => Err(anyhow!("sshd directive '{}' is not permitted", key)),
+                Some(_) if value.is_empty() || value.chars().any(|c| c.is_control()) => {
+                    Err(anyhow!("Invalid value for sshd directive '{}'", key))
+                }
+                Some(name) => Ok(Some((name.clone(), value.to_string()))),
+            }
+        },
+    )
+}
+
+fn render(directives: &BTreeMap<String, Resolved<String>>) -> String {
+    let mut dropin = String::from("# Managed by himmelblau\n");
+    for (name, directive) in directives {
+        dropin.push_str(&format!("{} {}\n", name, directive.value));
+    }
+    dropin
+}
+
+async fn run(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to execute {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// The systemd unit of sshd on this host, if any.
+async fn sshd_unit() -> Option<&'static str> {
+    for unit in SSHD_UNITS {
+        let output = Command::new("systemctl")
+            .args(["show", "--property=LoadState", "--value", unit])
+            .output()
+            .await;
+        if output.is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "loaded") {
+            return Some(unit);
+        }
+    }
+    None
+}
+
+pub struct SshdCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for SshdCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        SshdCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "sshd"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// The sshd directives of all assigned policies are written to a single
+    /// drop-in in /etc/ssh/sshd_config.d. Only the directives listed in
+    /// sshd_allowed_directives are accepted. If sshd -t rejects the new
+    /// configuration, the previous drop-in is restored. If no directives are
+    /// assigned, the drop-in is removed, unless every assigned directive was
+    /// refused, in which case the previous drop-in is kept. A failed reload
+    /// of sshd is retried on the next refresh.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let mut errors = vec![];
+        let allowed = self.config.get_sshd_allowed_directives();
+        let directives = resolve_directives(policies, &allowed, &mut errors);
+
+        let path = Path::new(SSHD_DROPIN);
+        let previous = fs::read(path).await.ok();
+        if directives.is_empty() && !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply sshd policies: {}",
+                errors.join("; ")
+            ));
+        }
+        let changed = if directives.is_empty() {
+            if previous.is_some() {
+                fs::remove_file(path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+                forget_provenance(&provenance_registry(&self.config)?, &[path.to_path_buf()])
+                    .await?;
+                debug!("Removed sshd policy {}", SSHD_DROPIN);
+            }
+            previous.is_some()
+        } else {
+            self.warn_not_included().await;
+            let mut sources = BTreeSet::new();
+            let mut setting_ids = vec![];
+            for directive in directives.values() {
+                if let Some(policy) = policies.policy_statuses.get(directive.policy) {
+                    sources.insert(policy.policy_id.clone());
+                    if let Some(detail) = policy.details.get(directive.detail) {
+                        setting_ids.push(detail.setting_definition_item_id.clone());
+                    }
+                }
+            }
+            let provenance =
+                Provenance::new(&self.config, sources.into_iter().collect(), setting_ids)?;
+            install_file(
+                path,
+                render(&directives).as_bytes(),
+                0o644,
+                Some(&provenance),
+            )
+            .await?
+        };
+
+        if changed {
+            if let Err(e) = run("sshd", &["-t"]).await {
+                error!("sshd configuration is invalid, rolling back: {:?}", e);
+                self.rollback(previous.as_deref()).await?;
+                return Err(anyhow!("Failed to apply sshd policies: {}", e));
+            }
+            debug!("Updated sshd policy {}", SSHD_DROPIN);
+        } else {
+            debug!("sshd policy {} is unchanged", SSHD_DROPIN);
+        }
+
+        // The drop-in may be unchanged since a reload which failed
+        let reload_pending = self.reload_pending_path()?;
+        let mut reloaded = true;
+        if self.config.get_sshd_reload() && (changed || reload_pending.exists()) {
+            let res = match sshd_unit().await {
+                Some(unit) => run("systemctl", &["try-reload-or-restart", unit]).await,
+                None => Err(anyhow!("No sshd unit found, not reloading sshd")),
+            };
+            match res {
+                Ok(()) => {
+                    if reload_pending.exists() {
+                        fs::remove_file(&reload_pending).await.map_err(|e| {
+                            anyhow!("Failed to remove {}: {}", reload_pending.display(), e)
+                        })?;
+                    }
+                }
+                Err(e) => {
+                    install_file(&reload_pending, b"", 0o600, None).await?;
+                    errors.push(e.to_string());
+                    reloaded = false;
+                }
+            }
+        }
+
+        for directive in directives.values() {
+            if let Some(detail) = policies
+                .policy_statuses
+                .get_mut(directive.policy)
+                .and_then(|policy| policy.details.get_mut(directive.detail))
+                .filter(|_| reloaded)
+            {
+                detail.actual_value = detail.expected_value.clone();
+                detail.new_compliance_state = "Compliant".to_string();
+            }
+            mark_overridden(
+                policies,
+                (directive.policy, directive.detail),
+                &directive.overridden,
+            );
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply sshd policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+impl SshdCSE {
+    /// Exists while sshd has not been reloaded since the drop-in changed.
+    fn reload_pending_path(&self) -> Result<PathBuf> {
+        let mut path = PathBuf::from(self.config.get_db_path());
+        if !path.pop() {
+            return Err(anyhow!("Failed to determine sshd state path"));
+        }
+        path.push("policy_sshd_reload_pending");
+        Ok(path)
+    }
+
+    /// The drop-ins are only read if sshd_config includes them.
+    async fn warn_not_included(&self) {
+        if let Ok(config) = fs::read_to_string(SSHD_CONFIG).await {
+            let included = config.lines().any(|line| {
+                let line = line.trim();
+                line.to_lowercase().starts_with("include") && line.contains("sshd_config.d")
+            });
+            if !included {
+                warn!(
+                    "{} does not include sshd_config.d, sshd policy will not apply",
+                    SSHD_CONFIG
+                );
+            }
+        }
+    }
+
+    /// Restore the previous drop-in, or remove it if there was none.
+    async fn rollback(&self, previous: Option<&[u8]>) -> Result<()> {
+        let path = PathBuf::from(SSHD_DROPIN);
+        match previous {
+            Some(previous) => {
+                install_file(&path, previous, 0o644, None).await?;
+            }
+            None => {
+                fs::remove_file(&path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+                forget_provenance(&provenance_registry(&self.config)?, &[path]).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_resolve_directives() {
+        let allowed: Vec<String> = ["PasswordAuthentication", "PermitRootLogin", "X11Forwarding"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy(
+                    "a",
+                    &[
+                        ("linux_sshd_passwordauthentication", "yes"),
+                        ("linux_sshd_x11forwarding", " no "),
+                    ],
+                ),
+                policy(
+                    "b",
+                    &[
+                        ("linux_sshd_passwordauthentication", "no"),
+                        ("linux_sshd_permitrootlogin", "no\nMatch all"),
+                        ("linux_sshd_authorizedkeyscommand", "/tmp/keys"),
+                    ],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let directives = resolve_directives(&policies, &allowed, &mut errors);
+        assert_eq!(errors.len(), 2);
+        assert!(directives
+            .get("PasswordAuthentication")
+            .is_some_and(|d| d.value == "no" && d.policy == 1));
+        assert_eq!(
+            render(&directives),
+            "# Managed by himmelblau\nPasswordAuthentication no\nX11Forwarding no\n"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Writes the outcome of the last policy refresh of each account as a
+ * Prometheus textfile, for collection by the node_exporter textfile
+ * collector. The metric names and help text are stable.
+ */
+use crate::files::install_file;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Mutex as StdMutex, OnceLock};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PolicyRunStatus {
+    /// Unix time the refresh finished.
+    pub timestamp: i64,
+    pub policies_applied: usize,
+    pub cse_failures: usize,
+    pub success: bool,
+}
+
+const METRICS: &[(&str, &str)] = &[
+    (
+        "himmelblau_policy_last_run_timestamp_seconds",
+        "Unix time of the last policy refresh.",
+    ),
+    (
+        "himmelblau_policy_policies_applied",
+        "Number of policies applied by the last policy refresh.",
+    ),
+    (
+        "himmelblau_policy_cse_failures",
+        "Number of client side extensions which failed during the last policy refresh.",
+    ),
+    (
+        "himmelblau_policy_last_run_success",
+        "Whether the last policy refresh succeeded (1) or failed (0).",
+    ),
+];
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(runs: &BTreeMap<String, PolicyRunStatus>) -> String {
+    let mut out = String::new();
+    for (i, (name, help)) in METRICS.iter().enumerate() {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n",
+            name, help, name
+        ));
+        for (account_id, run) in runs {
+            let value = match i {
+                0 => run.timestamp.to_string(),
+                1 => run.policies_applied.to_string(),
+                2 => run.cse_failures.to_string(),
+                _ => u8::from(run.success).to_string(),
+            };
+            out.push_str(&format!(
+                "{}{{account=\"{}\"}} {}\n",
+                name,
+                escape_label(account_id),
+                value
+            ));
+        }
+    }
+    out
+}
+
+/// Record the outcome of a refresh for `account_id`, and rewrite the
+/// textfile with the last refresh of every account seen by this process.
+pub async fn record_run(path: &Path, account_id: &str, run: PolicyRunStatus) -> Result<()> {
+    static RUNS: OnceLock<StdMutex<BTreeMap<String, PolicyRunStatus>>> = OnceLock::new();
+    // Serializes writers, so an older rendering never replaces a newer one
+    static WRITE: OnceLock<Mutex<()>> = OnceLock::new();
+
+    let _guard = WRITE.get_or_init(Default::default).lock().await;
+    let contents = {
+        let mut runs = match RUNS.get_or_init(Default::default).lock() {
+            Ok(runs) => runs,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        runs.insert(account_id.to_string(), run);
+        render(&runs)
+    };
+    install_file(path, contents.as_bytes(), 0o644, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let runs = BTreeMap::from([
+            (
+                "tux@example.com".to_string(),
+                PolicyRunStatus {
+                    timestamp: 1700000000,
+                    policies_applied: 3,
+                    cse_failures: 0,
+                    success: true,
+                },
+            ),
+            (
+                "a\"b@example.com".to_string(),
+                PolicyRunStatus {
+                    timestamp: 1700000100,
+                    policies_applied: 1,
+                    cse_failures: 2,
+                    success: false,
+                },
+            ),
+        ]);
+        let out = render(&runs);
+        assert!(out.starts_with(
+            "# HELP himmelblau_policy_last_run_timestamp_seconds Unix time of the last policy refresh.\n\
+            # TYPE himmelblau_policy_last_run_timestamp_seconds gauge\n\
+            himmelblau_policy_last_run_timestamp_seconds{account=\"a\\\"b@example.com\"} 1700000100\n\
+            himmelblau_policy_last_run_timestamp_seconds{account=\"tux@example.com\"} 1700000000\n"
+        ));
+        assert!(out.contains("himmelblau_policy_cse_failures{account=\"a\\\"b@example.com\"} 2\n"));
+        assert!(out.ends_with(
+            "himmelblau_policy_last_run_success{account=\"a\\\"b@example.com\"} 0\n\
+            himmelblau_policy_last_run_success{account=\"tux@example.com\"} 1\n"
+        ));
+    }
+}
@@ -0,0 +1,327 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{
+    forget_provenance, install_file, load_provenance, provenance_registry, Provenance,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::{IntuneStatus, PolicyStatus};
+use himmelblau_unix_common::config::HimmelblauConfig;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, error};
+
+const SETTING_PREFIX: &str = "linux_sudoers_";
+const SUDOERS_DIR: &str = "/etc/sudoers.d";
+const DROPIN_PREFIX: &str = "himmelblau-";
+
+/// Render the sudoers drop-in of a policy. Each linux_sudoers_* setting is a
+/// single sudoers line, e.g. "%admins ALL=(ALL) ALL", and the lines are
+/// written in the order of their setting ids.
+fn render_dropin(policy: &PolicyStatus) -> Result<String> {
+    let mut rules: Vec<(&str, &str)> = policy
+        .details
+        .iter()
+        .filter(|detail| {
+            detail
+                .setting_definition_item_id
+                .starts_with(SETTING_PREFIX)
+        })
+        .map(|detail| {
+            (
+                detail.setting_definition_item_id.as_str(),
+                detail.expected_value.trim(),
+            )
+        })
+        .collect();
+    rules.sort();
+
+    let mut dropin = format!("# Managed by himmelblau, policy {}\n", policy.policy_id);
+    for (id, rule) in rules {
+        if rule.is_empty() || rule.chars().any(|c| c.is_control()) {
+            return Err(anyhow!("Invalid sudoers rule in setting {}", id));
+        }
+        dropin.push_str(rule);
+        dropin.push('\n');
+    }
+    Ok(dropin)
+}
+
+fn dropin_name(policy_id: &str) -> Result<String> {
+    if policy_id.is_empty()
+        || !policy_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!("Invalid policy id '{}'", policy_id));
+    }
+    Ok(format!("{}{}", DROPIN_PREFIX, policy_id))
+}
+
+/// Whether `path` is named like a managed drop-in.
+fn is_dropin(path: &Path) -> bool {
+    path.parent() == Some(Path::new(SUDOERS_DIR))
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(DROPIN_PREFIX))
+}
+
+async fn visudo(args: &[&str]) -> Result<()> {
+    let output = Command::new("visudo")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to execute visudo: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "visudo {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stdout).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Check the syntax of a drop-in before it is installed. sudo skips files in
+/// sudoers.d whose name contains a '.', so the staged copy is never read.
+async fn check_dropin(name: &str, dropin: &str) -> Result<()> {
+    let staging = Path::new(SUDOERS_DIR).join(format!(".{}", name));
+    install_file(&staging, dropin.as_bytes(), 0o440, None).await?;
+    let res = visudo(&["-c", "-q", "-f", &staging.display().to_string()]).await;
+    let _ = fs::remove_file(&staging).await;
+    res
+}
+
+pub struct SudoersCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for SudoersCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        SudoersCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "sudoers"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// The sudoers rules of each policy are written to a managed drop-in in
+    /// /etc/sudoers.d, after visudo has checked their syntax. Drop-ins of
+    /// policies which are no longer assigned are removed, provided the
+    /// provenance registry records them as installed by himmelblau. If the
+    /// complete sudoers configuration is invalid afterwards, the drop-ins
+    /// installed and removed by this refresh are rolled back, so sudo is
+    /// never left unusable.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let mut managed = HashSet::new();
+        // The drop-ins written, with their previous contents
+        let mut installed: Vec<(PathBuf, Option<Vec<u8>>)> = vec![];
+        let mut applied = vec![];
+        let mut errors = vec![];
+
+        for (idx, policy) in policies.policy_statuses.iter().enumerate() {
+            // Validate this is a sudoers policy
+            if !policy
+                .details
+                .iter()
+                .any(|d| d.setting_definition_item_id.starts_with(SETTING_PREFIX))
+            {
+                continue;
+            }
+            let name = match dropin_name(&policy.policy_id) {
+                Ok(name) => name,
+                Err(e) => {
+                    errors.push(e.to_string());
+                    continue;
+                }
+            };
+            let path = Path::new(SUDOERS_DIR).join(&name);
+            // Keep a previously installed drop-in if this one fails
+            managed.insert(path.clone());
+            let previous = fs::read(&path).await.ok();
+            let res = async {
+                let dropin = render_dropin(policy)?;
+                check_dropin(&name, &dropin).await?;
+                let provenance = Provenance::for_policy(&self.config, policy, SETTING_PREFIX)?;
+                install_file(&path, dropin.as_bytes(), 0o440, Some(&provenance)).await
+            }
+            .await;
+            match res {
+                Ok(updated) => {
+                    if updated {
+                        debug!("Updated sudoers drop-in {}", path.display());
+                        installed.push((path, previous));
+                    } else {
+                        debug!("sudoers drop-in {} is unchanged", path.display());
+                    }
+                    applied.push(idx);
+                }
+                Err(e) => {
+                    error!("Skipping sudoers policy {}: {:?}", policy.policy_id, e);
+                    errors.push(format!("{}: {}", policy.policy_id, e));
+                }
+            }
+        }
+
+        // The drop-ins removed, with their previous contents (None if they
+        // were already gone)
+        let mut removed: Vec<(PathBuf, Option<Vec<u8>>)> = vec![];
+        let registry = provenance_registry(&self.config)?;
+        for key in load_provenance(&registry).await?.into_keys() {
+            let path = PathBuf::from(key);
+            if !is_dropin(&path) || managed.contains(&path) {
+                continue;
+            }
+            let previous = fs::read(&path).await.ok();
+            if previous.is_some() {
+                if let Err(e) = fs::remove_file(&path).await {
+                    errors.push(format!("Failed to remove {}: {}", path.display(), e));
+                    continue;
+                }
+                debug!("Removed sudoers drop-in {}", path.display());
+            }
+            removed.push((path, previous));
+        }
+
+        if !installed.is_empty() || removed.iter().any(|(_, previous)| previous.is_some()) {
+            if let Err(e) = visudo(&["-c", "-q"]).await {
+                error!("sudoers configuration is invalid, rolling back: {:?}", e);
+                self.rollback(&installed, &removed).await?;
+                return Err(anyhow!("Failed to apply sudoers policies: {}", e));
+            }
+        }
+        let removed: Vec<PathBuf> = removed.into_iter().map(|(path, _)| path).collect();
+        forget_provenance(&registry, &removed).await?;
+
+        for idx in applied {
+            if let Some(policy) = policies.policy_statuses.get_mut(idx) {
+                for detail in policy.details.iter_mut() {
+                    if detail
+                        .setting_definition_item_id
+                        .starts_with(SETTING_PREFIX)
+                    {
+                        detail.actual_value = detail.expected_value.clone();
+                        detail.new_compliance_state = "Compliant".to_string();
+                    }
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply sudoers policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+impl SudoersCSE {
+    /// Restore the previous contents of the drop-ins, and remove those which
+    /// did not exist before. Removed drop-ins keep their provenance until
+    /// the removal is committed, so restoring them is enough.
+    async fn rollback(
+        &self,
+        installed: &[(PathBuf, Option<Vec<u8>>)],
+        removed: &[(PathBuf, Option<Vec<u8>>)],
+    ) -> Result<()> {
+        for (path, previous) in removed {
+            if let Some(previous) = previous {
+                install_file(path, previous, 0o440, None).await?;
+            }
+        }
+        let mut new = vec![];
+        for (path, previous) in installed {
+            match previous {
+                Some(previous) => {
+                    install_file(path, previous, 0o440, None).await?;
+                }
+                None => {
+                    fs::remove_file(path)
+                        .await
+                        .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+                    new.push(path.clone());
+                }
+            }
+        }
+        forget_provenance(&provenance_registry(&self.config)?, &new).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_render_dropin() {
+        let dropin = render_dropin(&policy(
+            "0a1b",
+            &[
+                (
+                    "linux_sudoers_rule2",
+                    "%helpdesk ALL=(root) /usr/bin/systemctl",
+                ),
+                ("linux_sudoers_rule1", " %admins ALL=(ALL) ALL\n"),
+                ("linux_mount_where", "/mnt"),
+            ],
+        ));
+        assert_eq!(
+            dropin.ok().as_deref(),
+            Some(
+                "# Managed by himmelblau, policy 0a1b\n\
+                %admins ALL=(ALL) ALL\n\
+                %helpdesk ALL=(root) /usr/bin/systemctl\n"
+            )
+        );
+
+        assert!(render_dropin(&policy(
+            "0a1b",
+            &[(
+                "linux_sudoers_rule1",
+                "%admins ALL=(ALL) ALL\nroot ALL=(ALL) ALL"
+            )]
+        ))
+        .is_err());
+        assert!(render_dropin(&policy("0a1b", &[("linux_sudoers_rule1", " ")])).is_err());
+    }
+
+    #[test]
+    fn test_dropin_name() {
+        assert!(dropin_name("6f1c0c1e-2b9a-4d6e-8f27-0c6d8e2f1a3b")
+            .is_ok_and(|name| name == "himmelblau-6f1c0c1e-2b9a-4d6e-8f27-0c6d8e2f1a3b"));
+        assert!(dropin_name("").is_err());
+        assert!(dropin_name("a.b").is_err());
+        assert!(dropin_name("../sudoers").is_err());
+
+        assert!(is_dropin(Path::new("/etc/sudoers.d/himmelblau-0a1b")));
+        assert!(!is_dropin(Path::new("/etc/sudoers.d/admins")));
+        assert!(!is_dropin(Path::new("/etc/himmelblau-0a1b")));
+    }
+}
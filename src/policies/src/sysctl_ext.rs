@@ -0,0 +1,328 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software; you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation; either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::cse::CSE;
+use crate::files::{forget_provenance, install_file, provenance_registry, Provenance};
+use crate::resolve::{mark_overridden, resolve, Resolved};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use himmelblau::intune::IntuneStatus;
+use himmelblau_unix_common::config::HimmelblauConfig;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+const SETTING_PREFIX: &str = "linux_sysctl_";
+const PROC_SYS: &str = "/proc/sys";
+// The drop-ins are applied in lexical order, so this one sorts late to take
+// precedence over the distribution defaults
+const SYSCTL_DROPIN: &str = "/etc/sysctl.d/99-himmelblau.conf";
+
+/// The path of a kernel parameter below `proc_sys`, if the key is well formed.
+fn parameter_path(proc_sys: &Path, key: &str) -> Option<PathBuf> {
+    let mut path = proc_sys.to_path_buf();
+    for component in key.split('.') {
+        if component.is_empty()
+            || component == ".."
+            || !component
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return None;
+        }
+        path.push(component);
+    }
+    Some(path)
+}
+
+/// Collect the kernel parameters set by all policies. Each linux_sysctl_*
+/// setting sets the parameter named by the rest of its id, e.g.
+/// linux_sysctl_net.ipv4.ip_forward = 0. Parameters which do not exist below
+/// `proc_sys` are skipped with a warning. Settings with invalid values are
+/// added to `errors`.
+fn resolve_parameters(
+    policies: &IntuneStatus,
+    proc_sys: &Path,
+    errors: &mut Vec<String>,
+) -> BTreeMap<String, Resolved<String>> {
+    resolve(
+        policies,
+        SETTING_PREFIX,
+        "Kernel parameter",
+        errors,
+        |key, value| {
+            if !parameter_path(proc_sys, key).is_some_and(|path| path.is_file()) {
+                warn!("Skipping unknown kernel parameter {}", key);
+                return Ok(None);
+            }
+            let value = value.trim();
+            if value.is_empty() || value.chars().any(|c| c.is_control()) {
+                return Err(anyhow!("Invalid value for kernel parameter '{}'", key));
+            }
+            Ok(Some((key.to_string(), value.to_string())))
+        },
+    )
+}
+
+fn render(parameters: &BTreeMap<String, Resolved<String>>) -> String {
+    let mut dropin = String::from("# Managed by himmelblau\n");
+    for (key, parameter) in parameters {
+        dropin.push_str(&format!("{} = {}\n", key, parameter.value));
+    }
+    dropin
+}
+
+async fn sysctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("sysctl")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to execute sysctl: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "sysctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+pub struct SysctlCSE {
+    config: HimmelblauConfig,
+}
+
+#[async_trait]
+impl CSE for SysctlCSE {
+    fn new(config: &HimmelblauConfig, _username: &str) -> Self {
+        SysctlCSE {
+            config: config.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "sysctl"
+    }
+
+    fn setting_prefixes(&self) -> &'static [&'static str] {
+        &[SETTING_PREFIX]
+    }
+
+    /// The kernel parameters of all assigned policies are written to a single
+    /// drop-in in /etc/sysctl.d and applied with sysctl -p. The value each
+    /// parameter had before it was first managed is remembered, and restored
+    /// once no policy sets the parameter anymore.
+    async fn process_group_policy(&self, policies: &mut IntuneStatus) -> Result<bool> {
+        let state_path = self.state_path()?;
+        let mut originals: BTreeMap<String, String> = match fs::read_to_string(&state_path).await {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(_) => BTreeMap::new(),
+        };
+        let mut errors = vec![];
+        let proc_sys = Path::new(PROC_SYS);
+        let parameters = resolve_parameters(policies, proc_sys, &mut errors);
+        let path = Path::new(SYSCTL_DROPIN);
+        if parameters.is_empty() && errors.is_empty() && originals.is_empty() && !path.exists() {
+            return Ok(true);
+        }
+
+        for key in parameters.keys() {
+            if originals.contains_key(key) {
+                continue;
+            }
+            if let Some(path) = parameter_path(proc_sys, key) {
+                match fs::read_to_string(&path).await {
+                    Ok(value) => {
+                        originals.insert(key.clone(), value.trim().to_string());
+                    }
+                    Err(e) => warn!("Failed to read kernel parameter {}: {}", key, e),
+                }
+            }
+        }
+
+        if parameters.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+                forget_provenance(&provenance_registry(&self.config)?, &[path.to_path_buf()])
+                    .await?;
+                debug!("Removed sysctl policy {}", SYSCTL_DROPIN);
+            }
+        } else {
+            let mut sources = BTreeSet::new();
+            let mut setting_ids = vec![];
+            for parameter in parameters.values() {
+                if let Some(policy) = policies.policy_statuses.get(parameter.policy) {
+                    sources.insert(policy.policy_id.clone());
+                    if let Some(detail) = policy.details.get(parameter.detail) {
+                        setting_ids.push(detail.setting_definition_item_id.clone());
+                    }
+                }
+            }
+            let provenance =
+                Provenance::new(&self.config, sources.into_iter().collect(), setting_ids)?;
+            if install_file(
+                path,
+                render(&parameters).as_bytes(),
+                0o644,
+                Some(&provenance),
+            )
+            .await?
+            {
+                debug!("Updated sysctl policy {}", SYSCTL_DROPIN);
+            }
+            // Always apply, a parameter may have been changed at runtime
+            if let Err(e) = sysctl(&["-q", "-p", SYSCTL_DROPIN]).await {
+                errors.push(e.to_string());
+            }
+        }
+
+        // Revert the parameters which are no longer assigned
+        let retracted: Vec<(String, String)> = originals
+            .iter()
+            .filter(|(key, _)| !parameters.contains_key(*key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        for (key, value) in retracted {
+            let assignment = format!("{}={}", key, value);
+            match sysctl(&["-q", "-w", &assignment]).await {
+                Ok(()) => {
+                    debug!("Restored kernel parameter {}", assignment);
+                    originals.remove(&key);
+                }
+                Err(e) if !parameter_path(proc_sys, &key).is_some_and(|path| path.exists()) => {
+                    // The parameter is gone (e.g. its module was unloaded)
+                    debug!("Forgetting kernel parameter {}: {:?}", key, e);
+                    originals.remove(&key);
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        fs::write(&state_path, serde_json::to_string_pretty(&originals)?)
+            .await
+            .map_err(|e| anyhow!("Failed to save sysctl state: {}", e))?;
+
+        for parameter in parameters.values() {
+            if let Some(detail) = policies
+                .policy_statuses
+                .get_mut(parameter.policy)
+                .and_then(|policy| policy.details.get_mut(parameter.detail))
+            {
+                detail.actual_value = detail.expected_value.clone();
+                detail.new_compliance_state = "Compliant".to_string();
+            }
+            mark_overridden(
+                policies,
+                (parameter.policy, parameter.detail),
+                &parameter.overridden,
+            );
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to apply sysctl policies: {}",
+                errors.join("; ")
+            ));
+        }
+        Ok(true)
+    }
+}
+
+impl SysctlCSE {
+    fn state_path(&self) -> Result<PathBuf> {
+        let mut path = PathBuf::from(self.config.get_db_path());
+        if !path.pop() {
+            return Err(anyhow!("Failed to determine sysctl state path"));
+        }
+        path.push("policy_sysctl.json");
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::tests::policy;
+
+    #[test]
+    fn test_parameter_path() {
+        let proc_sys = Path::new(PROC_SYS);
+        assert_eq!(
+            parameter_path(proc_sys, "net.ipv4.ip_forward"),
+            Some(PathBuf::from("/proc/sys/net/ipv4/ip_forward"))
+        );
+        assert_eq!(
+            parameter_path(proc_sys, "net.ipv4.conf.all.rp_filter"),
+            Some(PathBuf::from("/proc/sys/net/ipv4/conf/all/rp_filter"))
+        );
+        assert!(parameter_path(proc_sys, "").is_none());
+        assert!(parameter_path(proc_sys, "kernel..modprobe").is_none());
+        assert!(parameter_path(proc_sys, "kernel/../../etc/shadow").is_none());
+    }
+
+    #[test]
+    fn test_resolve_parameters() -> Result<()> {
+        let proc_sys =
+            std::env::temp_dir().join(format!("himmelblau-sysctl-{}", std::process::id()));
+        std::fs::create_dir_all(proc_sys.join("kernel"))?;
+        std::fs::create_dir_all(proc_sys.join("net/ipv4"))?;
+        std::fs::write(proc_sys.join("kernel/kptr_restrict"), "0\n")?;
+        std::fs::write(proc_sys.join("net/ipv4/ip_forward"), "1\n")?;
+
+        let policies = IntuneStatus {
+            device_id: None,
+            policy_statuses: vec![
+                policy(
+                    "a",
+                    &[
+                        ("linux_sysctl_kernel.kptr_restrict", "1"),
+                        ("linux_sysctl_net.ipv4.ip_forward", "1"),
+                    ],
+                ),
+                policy(
+                    "b",
+                    &[
+                        ("linux_sysctl_kernel.kptr_restrict", " 2 "),
+                        ("linux_sysctl_kernel.no_such_parameter", "1"),
+                        (
+                            "linux_sysctl_net.ipv4.ip_forward",
+                            "0\nkernel.modprobe = /tmp/x",
+                        ),
+                    ],
+                ),
+            ],
+        };
+        let mut errors = vec![];
+        let parameters = resolve_parameters(&policies, &proc_sys, &mut errors);
+        std::fs::remove_dir_all(&proc_sys)?;
+
+        // The unknown parameter is skipped, not an error
+        assert_eq!(errors.len(), 1);
+        assert!(parameters
+            .get("kernel.kptr_restrict")
+            .is_some_and(|p| p.value == "2" && p.policy == 1));
+        assert_eq!(
+            render(&parameters),
+            "# Managed by himmelblau\nkernel.kptr_restrict = 2\nnet.ipv4.ip_forward = 1\n"
+        );
+        Ok(())
+    }
+}